@@ -50,22 +50,50 @@ pub fn main() {
     let payb_linenr = payloads_v(&fp_map);
     let map = port_payload_map(pb_linenr, payb_linenr);
 
-    generate_code(map);
+    let mut probes_path = env::current_dir().expect("cant find curr dir");
+    probes_path.push("./nmap-service-probes");
+    let probes_data = fs::read_to_string(&probes_path).expect("File not found.");
+    let service_probes = service_probes(&probes_data);
+
+    generate_code(map, &service_probes);
+}
+
+/// A probe of the service probe database, with the rules matching its responses.
+struct ServiceProbe {
+    name: String,
+    payload: Vec<u8>,
+    ports: Vec<u16>,
+    rarity: u8,
+    matches: Vec<ServiceMatch>,
+}
+
+/// A match or softmatch line of the service probe database.
+struct ServiceMatch {
+    service: String,
+    pattern: String,
+    soft: bool,
+    product: Option<String>,
+    version: Option<String>,
+    info: Option<String>,
 }
 
 /// Generates a file called Generated.rs and calls cargo fmt from the command line
 ///
 /// # Arguments
 ///
-/// * `port_payload_map` - A BTreeMap mapping port numbers to payload data
-fn generate_code(port_payload_map: BTreeMap<Vec<u16>, Vec<u8>>) {
+/// * `port_payload_map` - A BTreeMap mapping port numbers to every payload meant for them
+/// * `service_probes` - The probes parsed out of the service probe database
+fn generate_code(
+    port_payload_map: BTreeMap<Vec<u16>, Vec<Vec<u8>>>,
+    service_probes: &[ServiceProbe],
+) {
     let dest_path = PathBuf::from("src/generated.rs");
 
     let mut generated_code = String::new();
     generated_code.push_str("use std::collections::BTreeMap;\n");
     generated_code.push_str("use once_cell::sync::Lazy;\n\n");
 
-    generated_code.push_str("fn generated_data() -> BTreeMap<Vec<u16>, Vec<u8>> {\n");
+    generated_code.push_str("fn generated_data() -> BTreeMap<Vec<u16>, Vec<Vec<u8>>> {\n");
     generated_code.push_str("    let mut map = BTreeMap::new();\n");
 
     for (ports, payloads) in port_payload_map {
@@ -81,7 +109,14 @@ fn generate_code(port_payload_map: BTreeMap<Vec<u16>, Vec<u8>>) {
         generated_code.push_str(
             &payloads
                 .iter()
-                .map(|&p| p.to_string())
+                .map(|payload| {
+                    let bytes = payload
+                        .iter()
+                        .map(|&p| p.to_string())
+                        .collect::<Vec<_>>()
+                        .join(",");
+                    format!("vec![{bytes}]")
+                })
                 .collect::<Vec<_>>()
                 .join(","),
         );
@@ -92,11 +127,14 @@ fn generate_code(port_payload_map: BTreeMap<Vec<u16>, Vec<u8>>) {
     generated_code.push_str("}\n\n");
 
     generated_code.push_str(
-        "static PARSED_DATA: Lazy<BTreeMap<Vec<u16>, Vec<u8>>> = Lazy::new(generated_data);\n",
+        "static PARSED_DATA: Lazy<BTreeMap<Vec<u16>, Vec<Vec<u8>>>> = Lazy::new(generated_data);\n",
     );
-    generated_code.push_str("pub fn get_parsed_data() -> &'static BTreeMap<Vec<u16>, Vec<u8>> {\n");
+    generated_code
+        .push_str("pub fn get_parsed_data() -> &'static BTreeMap<Vec<u16>, Vec<Vec<u8>>> {\n");
     generated_code.push_str("    &PARSED_DATA\n");
-    generated_code.push_str("}\n");
+    generated_code.push_str("}\n\n");
+
+    generated_code.push_str(&service_probes_code(service_probes));
 
     fs::write(dest_path, generated_code).unwrap();
 
@@ -215,20 +253,285 @@ fn parser(payload: &str) -> Vec<u8> {
 ///
 /// # Returns
 ///
-/// A BTreeMap mapping vectors of ports to vectors of payload bytes
+/// A BTreeMap mapping vectors of ports to every payload defined for them, in
+/// file order. Entries for the same ports add payloads rather than replacing them.
 fn port_payload_map(
     pb_linenr: BTreeMap<i32, Vec<u16>>,
     payb_linenr: BTreeMap<i32, Vec<u8>>,
-) -> BTreeMap<Vec<u16>, Vec<u8>> {
-    let mut ppm_fin: BTreeMap<Vec<u16>, Vec<u8>> = BTreeMap::new();
+) -> BTreeMap<Vec<u16>, Vec<Vec<u8>>> {
+    let mut ppm_fin: BTreeMap<Vec<u16>, Vec<Vec<u8>>> = BTreeMap::new();
 
     for (port_linenr, ports) in pb_linenr {
-        for (pay_linenr, payloads) in &payb_linenr {
-            if pay_linenr == &port_linenr {
-                ppm_fin.insert(ports.to_vec(), payloads.to_vec());
-            }
+        if let Some(payload) = payb_linenr.get(&port_linenr) {
+            ppm_fin.entry(ports).or_default().push(payload.to_vec());
         }
     }
 
     ppm_fin
 }
+
+/// Generates the code holding the service probe database as static data
+///
+/// # Arguments
+///
+/// * `service_probes` - The probes parsed out of the service probe database
+///
+/// # Returns
+///
+/// The Rust source defining the probe types, the data and its accessor
+fn service_probes_code(service_probes: &[ServiceProbe]) -> String {
+    let mut code = String::new();
+
+    code.push_str("/// A probe of the service probe database, see `nmap-service-probes`.\n");
+    code.push_str("#[derive(Debug)]\n");
+    code.push_str("pub struct ServiceProbeData {\n");
+    code.push_str("    pub name: &'static str,\n");
+    code.push_str("    pub payload: &'static [u8],\n");
+    code.push_str("    pub ports: &'static [u16],\n");
+    code.push_str("    pub rarity: u8,\n");
+    code.push_str("    pub matches: &'static [ServiceMatchData],\n");
+    code.push_str("}\n\n");
+
+    code.push_str("/// A match or softmatch rule of a probe.\n");
+    code.push_str("#[derive(Debug)]\n");
+    code.push_str("pub struct ServiceMatchData {\n");
+    code.push_str("    pub service: &'static str,\n");
+    code.push_str("    pub pattern: &'static str,\n");
+    code.push_str("    pub soft: bool,\n");
+    code.push_str("    pub product: Option<&'static str>,\n");
+    code.push_str("    pub version: Option<&'static str>,\n");
+    code.push_str("    pub info: Option<&'static str>,\n");
+    code.push_str("}\n\n");
+
+    code.push_str("static SERVICE_PROBES: &[ServiceProbeData] = &[\n");
+    for probe in service_probes {
+        code.push_str(&format!(
+            "    ServiceProbeData {{ name: {:?}, payload: &{:?}, ports: &{:?}, rarity: {}, matches: &[\n",
+            probe.name, probe.payload, probe.ports, probe.rarity
+        ));
+        for service_match in &probe.matches {
+            code.push_str(&format!(
+                "        ServiceMatchData {{ service: {:?}, pattern: {:?}, soft: {}, product: {:?}, version: {:?}, info: {:?} }},\n",
+                service_match.service,
+                service_match.pattern,
+                service_match.soft,
+                service_match.product,
+                service_match.version,
+                service_match.info
+            ));
+        }
+        code.push_str("    ] },\n");
+    }
+    code.push_str("];\n\n");
+
+    code.push_str("pub fn get_service_probes() -> &'static [ServiceProbeData] {\n");
+    code.push_str("    SERVICE_PROBES\n");
+    code.push_str("}\n");
+
+    code
+}
+
+/// Parses the service probe database, which follows the nmap-service-probes format
+///
+/// # Arguments
+///
+/// * `data` - The content of the service probe database
+///
+/// # Returns
+///
+/// The TCP probes in the order they appear in the file
+fn service_probes(data: &str) -> Vec<ServiceProbe> {
+    let mut probes: Vec<ServiceProbe> = Vec::new();
+
+    for line in data.lines() {
+        let line = line.trim();
+        if line.starts_with('#') || line.is_empty() {
+            continue;
+        }
+
+        let (directive, rest) = line.split_once(' ').unwrap_or((line, ""));
+        match directive {
+            "Probe" => {
+                let mut parts = rest.splitn(3, ' ');
+                let protocol = parts.next().unwrap_or_default();
+                let name = parts.next().expect("probe name not found");
+                let payload = parts.next().expect("probe payload not found");
+                let (payload, _) = delimited(
+                    payload
+                        .strip_prefix('q')
+                        .expect("payload must start with q"),
+                );
+
+                // Only TCP probes are sent, the lines following any other
+                // probe are attached to an unnamed one removed at the end.
+                let name = if protocol == "TCP" { name } else { "" };
+                probes.push(ServiceProbe {
+                    name: name.to_owned(),
+                    payload: unescape(payload),
+                    ports: Vec::new(),
+                    rarity: 1,
+                    matches: Vec::new(),
+                });
+            }
+            "ports" => {
+                let probe = probes.last_mut().expect("ports found before any probe");
+                probe.ports = probe_ports(rest);
+            }
+            "rarity" => {
+                let probe = probes.last_mut().expect("rarity found before any probe");
+                probe.rarity = rest.trim().parse().expect("rarity must be a number");
+            }
+            "match" | "softmatch" => {
+                let probe = probes.last_mut().expect("match found before any probe");
+                probe
+                    .matches
+                    .push(service_match(rest, directive == "softmatch"));
+            }
+            _ => {}
+        }
+    }
+
+    probes.retain(|probe| !probe.name.is_empty());
+    probes
+}
+
+/// Parses what follows `match` or `softmatch` on a line
+///
+/// # Arguments
+///
+/// * `line` - The service name, the pattern and the version fields
+/// * `soft` - Whether the line is a softmatch
+///
+/// # Returns
+///
+/// The match with its flags folded into the pattern
+fn service_match(line: &str, soft: bool) -> ServiceMatch {
+    let (service, rest) = line.split_once(' ').expect("match without pattern");
+    let (pattern, rest) = delimited(rest.strip_prefix('m').expect("pattern must start with m"));
+
+    // Flags directly follow the closing delimiter of the pattern.
+    let flags_end = rest.find(' ').unwrap_or(rest.len());
+    let (flags, mut rest) = rest.split_at(flags_end);
+    let mut inline_flags = String::new();
+    for flag in flags.chars() {
+        match flag {
+            'i' | 's' => inline_flags.push(flag),
+            _ => panic!("unknown pattern flag {}", flag),
+        }
+    }
+    let pattern = if inline_flags.is_empty() {
+        pattern.to_owned()
+    } else {
+        format!("(?{}){}", inline_flags, pattern)
+    };
+
+    let mut service_match = ServiceMatch {
+        service: service.to_owned(),
+        pattern,
+        soft,
+        product: None,
+        version: None,
+        info: None,
+    };
+
+    loop {
+        rest = rest.trim_start();
+        if rest.is_empty() {
+            break;
+        }
+
+        let field_end = if rest.starts_with("cpe:") { 4 } else { 1 };
+        let (field, value) = rest.split_at(field_end);
+        let (value, remaining) = delimited(value);
+        // cpe values may end with an `a` flag.
+        rest = remaining.strip_prefix('a').unwrap_or(remaining);
+
+        let value = Some(value.to_owned());
+        match field {
+            "p" => service_match.product = value,
+            "v" => service_match.version = value,
+            "i" => service_match.info = value,
+            _ => {}
+        }
+    }
+
+    service_match
+}
+
+/// Splits a string starting with a delimiter into what is enclosed by it and the remainder
+///
+/// # Arguments
+///
+/// * `input` - A string whose first character is the delimiter, like `|GET / HTTP/1.0|`
+///
+/// # Returns
+///
+/// The enclosed string and what follows the closing delimiter
+fn delimited(input: &str) -> (&str, &str) {
+    let delimiter = input.chars().next().expect("delimiter not found");
+    let inner = &input[delimiter.len_utf8()..];
+    let end = inner.find(delimiter).expect("closing delimiter not found");
+    (&inner[..end], &inner[end + delimiter.len_utf8()..])
+}
+
+/// Decodes the C style escapes used by probe payloads
+///
+/// # Arguments
+///
+/// * `payload` - The payload as written in the probe database
+///
+/// # Returns
+///
+/// The bytes to send
+fn unescape(payload: &str) -> Vec<u8> {
+    let mut bytes: Vec<u8> = Vec::new();
+    let mut chars = payload.chars();
+
+    while let Some(char) = chars.next() {
+        if char != '\\' {
+            let mut buf = [0u8; 4];
+            bytes.extend_from_slice(char.encode_utf8(&mut buf).as_bytes());
+            continue;
+        }
+
+        match chars.next() {
+            Some('r') => bytes.push(b'\r'),
+            Some('n') => bytes.push(b'\n'),
+            Some('t') => bytes.push(b'\t'),
+            Some('0') => bytes.push(0),
+            Some('x') => {
+                let hex: String = chars.by_ref().take(2).collect();
+                bytes.push(u8::from_str_radix(&hex, 16).expect("invalid hex escape"));
+            }
+            Some(other) => bytes.push(other as u8),
+            None => bytes.push(b'\\'),
+        }
+    }
+
+    bytes
+}
+
+/// Expands the ports line of a probe
+///
+/// # Arguments
+///
+/// * `ports` - Comma separated ports and ranges, like `80-85,443`
+///
+/// # Returns
+///
+/// Every port listed, ranges included
+fn probe_ports(ports: &str) -> Vec<u16> {
+    let mut port_list: Vec<u16> = Vec::new();
+
+    for segment in ports.trim().split(',') {
+        if let Some((start, end)) = segment.split_once('-') {
+            let start = start.parse::<u16>().unwrap();
+            let end = end.parse::<u16>().unwrap();
+            port_list.extend(start..=end);
+        } else if !segment.is_empty() {
+            port_list.push(segment.parse::<u16>().unwrap());
+        }
+    }
+
+    port_list
+}
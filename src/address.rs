@@ -1,21 +1,72 @@
 //! Provides functions to parse input IP addresses, CIDRs or files.
-use std::collections::BTreeSet;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
 use std::fs::{self, File};
 use std::io::{prelude::*, BufReader};
+use std::iter::FromIterator;
 use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
 use std::path::Path;
 use std::str::FromStr;
 
 use cidr_utils::cidr::IpCidr;
 use hickory_resolver::{
-    config::{NameServerConfig, Protocol, ResolverConfig, ResolverOpts},
+    config::{LookupIpStrategy, NameServerConfig, Protocol, ResolverConfig, ResolverOpts},
+    system_conf::read_system_conf,
     Resolver,
 };
 use log::debug;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
 
-use crate::input::Opts;
+use crate::input::{Opts, ResolvePolicy};
 use crate::warning;
 
+/// The IP addresses to scan, each linked to the hostnames it was resolved
+/// from.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Targets {
+    pub ips: TargetSet,
+    hostnames: BTreeMap<IpAddr, Vec<String>>,
+}
+
+impl Targets {
+    /// The hostnames `ip` was resolved from, none when it was given as an
+    /// address or CIDR.
+    pub fn hostnames(&self, ip: IpAddr) -> &[String] {
+        self.hostnames.get(&ip).map_or(&[], Vec::as_slice)
+    }
+
+    /// Links `ip` to a hostname it was resolved from, once.
+    pub fn link(&mut self, ip: IpAddr, hostname: &str) {
+        let hostnames = self.hostnames.entry(ip).or_default();
+        if !hostnames.iter().any(|linked| linked == hostname) {
+            hostnames.push(hostname.to_owned());
+        }
+    }
+
+    /// Adds the IPs `address` stands for, linked to it when it is a
+    /// hostname. Returns whether it stood for any.
+    fn add(&mut self, address: &str, resolver: &Resolver, policy: ResolvePolicy) -> bool {
+        let ips = parse_address(address, resolver, policy);
+        if IpCidr::from_str(address).is_err() {
+            for ip in &ips {
+                self.link(ip, address);
+            }
+        }
+        self.ips.insert_all(&ips);
+        !ips.is_empty()
+    }
+}
+
+impl From<TargetSet> for Targets {
+    /// Targets given as IPs, linked to no hostname.
+    fn from(ips: TargetSet) -> Self {
+        Self {
+            ips,
+            hostnames: BTreeMap::new(),
+        }
+    }
+}
+
 /// Parses the string(s) into IP addresses.
 ///
 /// Goes through all possible IP inputs (files or via argparsing).
@@ -29,17 +80,21 @@ use crate::warning;
 /// let ips = parse_addresses(&opts);
 /// ```
 ///
-/// Finally, any duplicates are removed to avoid excessive scans.
-pub fn parse_addresses(input: &Opts) -> Vec<IpAddr> {
-    let mut ips: Vec<IpAddr> = Vec::new();
+/// Finally, any duplicates are removed to avoid excessive scans. CIDRs are
+/// never expanded, the addresses are only yielded when iterating them.
+pub fn parse_addresses(input: &Opts) -> TargetSet {
+    parse_targets(input).ips
+}
+
+/// Same as `parse_addresses`, keeping track of the hostnames every IP was
+/// resolved from.
+pub fn parse_targets(input: &Opts) -> Targets {
+    let mut targets = Targets::default();
     let mut unresolved_addresses: Vec<&str> = Vec::new();
     let backup_resolver = get_resolver(&input.resolver);
 
     for address in &input.addresses {
-        let parsed_ips = parse_address(address, &backup_resolver);
-        if !parsed_ips.is_empty() {
-            ips.extend(parsed_ips);
-        } else {
+        if !targets.add(address, &backup_resolver, input.resolve) {
             unresolved_addresses.push(address);
         }
     }
@@ -58,9 +113,7 @@ pub fn parse_addresses(input: &Opts) -> Vec<IpAddr> {
             continue;
         }
 
-        if let Ok(x) = read_ips_from_file(file_path, &backup_resolver) {
-            ips.extend(x);
-        } else {
+        if read_ips_from_file(file_path, &backup_resolver, input.resolve, &mut targets).is_err() {
             warning!(
                 format!("Host {file_path:?} could not be resolved."),
                 input.greppable,
@@ -69,19 +122,22 @@ pub fn parse_addresses(input: &Opts) -> Vec<IpAddr> {
         }
     }
 
-    // Finally, craft a list of addresses to be excluded from the scan.
-    let mut excluded_ips: BTreeSet<IpAddr> = BTreeSet::new();
+    // Finally, craft a list of addresses to be excluded from the scan. An
+    // excluded hostname excludes all of its addresses.
+    let mut excluded_ips = TargetSet::new();
     if let Some(exclude_addresses) = &input.exclude_addresses {
         for addr in exclude_addresses {
-            excluded_ips.extend(parse_address(addr, &backup_resolver));
+            excluded_ips.insert_all(&parse_address(addr, &backup_resolver, ResolvePolicy::All));
         }
     }
 
-    // Remove duplicated/excluded IPs.
-    let mut seen = BTreeSet::new();
-    ips.retain(|ip| seen.insert(*ip) && !excluded_ips.contains(ip));
+    // Duplicates never made it into the set, only the excluded IPs are left.
+    targets.ips.remove_all(&excluded_ips);
+    targets
+        .hostnames
+        .retain(|ip, _| !excluded_ips.contains(*ip));
 
-    ips
+    targets
 }
 
 /// Given a string, parse it as a host, IP address, or CIDR.
@@ -89,40 +145,63 @@ pub fn parse_addresses(input: &Opts) -> Vec<IpAddr> {
 /// This allows us to pass files as hosts or cidr or IPs easily
 /// Call this every time you have a possible IP-or-host.
 ///
-/// If the address is a domain, we can self-resolve the domain locally
-/// or resolve it by dns resolver list.
+/// If the address is a domain, we can resolve it by dns resolver list or
+/// self-resolve the domain locally. The policy picks which of its addresses
+/// are kept.
 ///
 /// ```rust
 /// # use rustscan::address::parse_address;
+/// # use rustscan::input::ResolvePolicy;
 /// # use hickory_resolver::Resolver;
-/// let ips = parse_address("127.0.0.1", &Resolver::default().unwrap());
+/// let ips = parse_address("127.0.0.1", &Resolver::default().unwrap(), ResolvePolicy::All);
 /// ```
-pub fn parse_address(address: &str, resolver: &Resolver) -> Vec<IpAddr> {
-    IpCidr::from_str(address)
-        .map(|cidr| cidr.iter().map(|c| c.address()).collect())
-        .ok()
-        .or_else(|| {
-            format!("{}:80", &address)
-                .to_socket_addrs()
-                .ok()
-                .map(|mut iter| vec![iter.next().unwrap().ip()])
-        })
-        .unwrap_or_else(|| resolve_ips_from_host(address, resolver))
+pub fn parse_address(address: &str, resolver: &Resolver, policy: ResolvePolicy) -> TargetSet {
+    match IpCidr::from_str(address) {
+        Ok(cidr) => {
+            let mut ips = TargetSet::new();
+            ips.insert_cidr(&cidr);
+            ips
+        }
+        Err(_) => resolve_ips_from_host(address, resolver, policy)
+            .into_iter()
+            .collect(),
+    }
 }
 
-/// Uses DNS to get the IPS associated with host
-fn resolve_ips_from_host(source: &str, backup_resolver: &Resolver) -> Vec<IpAddr> {
+/// Uses DNS to get every A and AAAA record of host, asking the system when
+/// DNS knows nothing about it.
+fn resolve_ips_from_host(
+    source: &str,
+    backup_resolver: &Resolver,
+    policy: ResolvePolicy,
+) -> Vec<IpAddr> {
     let mut ips: Vec<IpAddr> = Vec::new();
 
-    if let Ok(addrs) = source.to_socket_addrs() {
-        for ip in addrs {
-            ips.push(ip.ip());
-        }
-    } else if let Ok(addrs) = backup_resolver.lookup_ip(source) {
+    if let Ok(addrs) = backup_resolver.lookup_ip(source) {
         ips.extend(addrs.iter());
     }
+    if ips.is_empty() {
+        if let Ok(addrs) = (source, 0).to_socket_addrs() {
+            ips.extend(addrs.map(|addr| addr.ip()));
+        }
+    }
+
+    select_ips(ips, policy)
+}
 
-    ips
+/// Keeps the addresses of a hostname the policy asks for, IPv4 ones coming
+/// first.
+fn select_ips(mut ips: Vec<IpAddr>, policy: ResolvePolicy) -> Vec<IpAddr> {
+    ips.sort_by_key(IpAddr::is_ipv6);
+    let mut seen = BTreeSet::new();
+    ips.retain(|ip| seen.insert(*ip));
+
+    match policy {
+        ResolvePolicy::All => ips,
+        ResolvePolicy::First => ips.into_iter().take(1).collect(),
+        ResolvePolicy::V4 => ips.into_iter().filter(IpAddr::is_ipv4).collect(),
+        ResolvePolicy::V6 => ips.into_iter().filter(IpAddr::is_ipv6).collect(),
+    }
 }
 
 /// Derive a DNS resolver.
@@ -135,7 +214,12 @@ fn resolve_ips_from_host(source: &str, backup_resolver: &Resolver) -> Vec<IpAddr
 ///       `/etc/resolv.conf` on *nix).
 ///    2. finally, build a CloudFlare-based resolver (default
 ///       behaviour).
+///
+/// Lookups ask for both A and AAAA records, the resolve policy picks among
+/// them afterwards.
 fn get_resolver(resolver: &Option<String>) -> Resolver {
+    let mut opts = ResolverOpts::default();
+    opts.ip_strategy = LookupIpStrategy::Ipv4AndIpv6;
     match resolver {
         Some(r) => {
             let mut config = ResolverConfig::new();
@@ -152,13 +236,14 @@ fn get_resolver(resolver: &Option<String>) -> Resolver {
                     Protocol::Udp,
                 ));
             }
-            Resolver::new(config, ResolverOpts::default()).unwrap()
+            Resolver::new(config, opts).unwrap()
         }
-        None => match Resolver::from_system_conf() {
-            Ok(resolver) => resolver,
-            Err(_) => {
-                Resolver::new(ResolverConfig::cloudflare_tls(), ResolverOpts::default()).unwrap()
+        None => match read_system_conf() {
+            Ok((config, mut system_opts)) => {
+                system_opts.ip_strategy = opts.ip_strategy;
+                Resolver::new(config, system_opts).unwrap()
             }
+            Err(_) => Resolver::new(ResolverConfig::cloudflare_tls(), opts).unwrap(),
         },
     }
 }
@@ -174,31 +259,371 @@ fn read_resolver_from_file(path: &str) -> Result<Vec<IpAddr>, std::io::Error> {
 }
 
 #[cfg(not(tarpaulin_include))]
-/// Parses an input file of IPs and uses those
+/// Parses an input file of IPs and adds those to the targets
 fn read_ips_from_file(
     ips: &std::path::Path,
     backup_resolver: &Resolver,
-) -> Result<Vec<IpAddr>, std::io::Error> {
+    policy: ResolvePolicy,
+    targets: &mut Targets,
+) -> Result<(), std::io::Error> {
     let file = File::open(ips)?;
     let reader = BufReader::new(file);
 
-    let mut ips: Vec<IpAddr> = Vec::new();
-
     for address_line in reader.lines() {
         if let Ok(address) = address_line {
-            ips.extend(parse_address(&address, backup_resolver));
+            targets.add(&address, backup_resolver, policy);
         } else {
             debug!("Line in file is not valid");
         }
     }
 
-    Ok(ips)
+    Ok(())
+}
+
+/// A set of IP addresses held as ranges, so that a CIDR costs the same
+/// whatever its size. Addresses are yielded on demand, in the order their
+/// ranges were inserted, each one only once.
+///
+/// ```rust
+/// # use rustscan::address::TargetSet;
+/// # use cidr_utils::cidr::IpCidr;
+/// # use std::str::FromStr;
+/// let mut targets = TargetSet::new();
+/// targets.insert_cidr(&IpCidr::from_str("10.0.0.0/8").unwrap());
+/// targets.remove_all(&TargetSet::from(&["10.0.0.1".parse().unwrap()]));
+///
+/// assert_eq!(targets.len(), 16_777_215);
+/// assert_eq!(targets.iter().nth(1), Some("10.0.0.2".parse().unwrap()));
+/// ```
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct TargetSet {
+    /// Disjoint ranges, in insertion order.
+    ranges: Vec<IpRange>,
+    /// The union of the ranges, merged and sorted, to find overlaps.
+    covered: BTreeMap<(bool, u128), u128>,
+}
+
+/// An inclusive range of addresses of one IP version, as numbers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct IpRange {
+    v6: bool,
+    start: u128,
+    end: u128,
+}
+
+impl IpRange {
+    fn len(self) -> u128 {
+        (self.end - self.start).saturating_add(1)
+    }
+}
+
+impl fmt::Display for IpRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let start = to_ip(self.v6, self.start);
+        if self.start == self.end {
+            write!(f, "{start}")
+        } else {
+            write!(f, "{start}-{}", to_ip(self.v6, self.end))
+        }
+    }
+}
+
+fn to_number(ip: IpAddr) -> (bool, u128) {
+    match ip {
+        IpAddr::V4(ip) => (false, u32::from(ip).into()),
+        IpAddr::V6(ip) => (true, ip.into()),
+    }
+}
+
+fn to_ip(v6: bool, number: u128) -> IpAddr {
+    if v6 {
+        IpAddr::V6(number.into())
+    } else {
+        // IPv4 ranges never go past u32::MAX.
+        IpAddr::V4((number as u32).into())
+    }
+}
+
+impl TargetSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of addresses, saturating for the whole IPv6 space.
+    pub fn len(&self) -> u128 {
+        self.ranges
+            .iter()
+            .fold(0, |len, range| len.saturating_add(range.len()))
+    }
+
+    /// Number of IPv4 addresses.
+    pub fn ipv4_len(&self) -> u128 {
+        self.ranges
+            .iter()
+            .filter(|range| !range.v6)
+            .map(|range| range.len())
+            .sum()
+    }
+
+    /// Number of IPv6 addresses, saturating for the whole IPv6 space.
+    pub fn ipv6_len(&self) -> u128 {
+        self.len() - self.ipv4_len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let (v6, number) = to_number(ip);
+        self.covered
+            .range(..=(v6, number))
+            .next_back()
+            .is_some_and(|(&(range_v6, _), &end)| range_v6 == v6 && number <= end)
+    }
+
+    /// Yields the addresses one at a time, without ever holding them all.
+    pub fn iter(&self) -> Iter<'_> {
+        Iter {
+            ranges: self.ranges.iter(),
+            current: None,
+        }
+    }
+
+    /// Gives access to the addresses by their position in iteration order.
+    pub fn indexed(&self) -> Indexed<'_> {
+        let mut next = 0u128;
+        let starts = self
+            .ranges
+            .iter()
+            .map(|range| {
+                let start = next;
+                next = next.saturating_add(range.len());
+                start
+            })
+            .collect();
+        Indexed {
+            ranges: &self.ranges,
+            starts,
+        }
+    }
+
+    pub fn insert(&mut self, ip: IpAddr) {
+        let (v6, number) = to_number(ip);
+        self.insert_range(IpRange {
+            v6,
+            start: number,
+            end: number,
+        });
+    }
+
+    pub fn insert_cidr(&mut self, cidr: &IpCidr) {
+        let (v6, start) = to_number(cidr.first_address());
+        let (_, end) = to_number(cidr.last_address());
+        self.insert_range(IpRange { v6, start, end });
+    }
+
+    /// Adds every address of `other`, after the ones already there.
+    pub fn insert_all(&mut self, other: &TargetSet) {
+        for range in &other.ranges {
+            self.insert_range(*range);
+        }
+    }
+
+    /// Takes out every address of `other`, splitting the ranges it cuts
+    /// through.
+    pub fn remove_all(&mut self, other: &TargetSet) {
+        self.ranges = self
+            .ranges
+            .iter()
+            .flat_map(|range| other.uncovered(*range))
+            .collect();
+        self.covered.clear();
+        for range in self.ranges.clone() {
+            self.cover(range);
+        }
+    }
+
+    fn insert_range(&mut self, range: IpRange) {
+        for piece in self.uncovered(range) {
+            // Neighbouring addresses, like the lines of a file, share a range.
+            match self.ranges.last_mut() {
+                Some(last)
+                    if last.v6 == piece.v6 && last.end.checked_add(1) == Some(piece.start) =>
+                {
+                    last.end = piece.end;
+                }
+                _ => self.ranges.push(piece),
+            }
+        }
+        self.cover(range);
+    }
+
+    /// The parts of `range` none of the ranges of the set hold.
+    fn uncovered(&self, range: IpRange) -> Vec<IpRange> {
+        let mut pieces = Vec::new();
+        let mut start = range.start;
+        // The range covering the start of `range`, if any, comes first.
+        let from = self
+            .covered
+            .range(..=(range.v6, range.start))
+            .next_back()
+            .map_or((range.v6, range.start), |(&key, _)| key);
+        for (&(v6, covered_start), &covered_end) in self.covered.range(from..=(range.v6, range.end))
+        {
+            if v6 != range.v6 || covered_end < start {
+                continue;
+            }
+            if covered_start > start {
+                pieces.push(IpRange {
+                    v6,
+                    start,
+                    end: covered_start - 1,
+                });
+            }
+            if covered_end >= range.end {
+                return pieces;
+            }
+            start = covered_end + 1;
+        }
+        pieces.push(IpRange { start, ..range });
+        pieces
+    }
+
+    /// Merges `range` into the union of the ranges.
+    fn cover(&mut self, range: IpRange) {
+        let mut start = range.start;
+        let mut end = range.end;
+        let touching: Vec<(bool, u128)> = self
+            .covered
+            .range(..=(range.v6, range.end.saturating_add(1)))
+            .rev()
+            .take_while(|(&(v6, _), &covered_end)| {
+                v6 == range.v6 && covered_end.saturating_add(1) >= range.start
+            })
+            .map(|(&key, _)| key)
+            .collect();
+        for key in touching {
+            let covered_end = self.covered.remove(&key).unwrap();
+            start = start.min(key.1);
+            end = end.max(covered_end);
+        }
+        self.covered.insert((range.v6, start), end);
+    }
+}
+
+/// Iterator over the addresses of a `TargetSet`.
+#[derive(Debug, Clone)]
+pub struct Iter<'s> {
+    ranges: std::slice::Iter<'s, IpRange>,
+    current: Option<IpRange>,
+}
+
+impl Iterator for Iter<'_> {
+    type Item = IpAddr;
+
+    fn next(&mut self) -> Option<IpAddr> {
+        loop {
+            if let Some(range) = &mut self.current {
+                let ip = to_ip(range.v6, range.start);
+                if range.start == range.end {
+                    self.current = None;
+                } else {
+                    range.start += 1;
+                }
+                return Some(ip);
+            }
+            self.current = Some(*self.ranges.next()?);
+        }
+    }
+}
+
+/// Addresses of a `TargetSet` by position, see `TargetSet::indexed`.
+#[derive(Debug, Clone)]
+pub struct Indexed<'s> {
+    ranges: &'s [IpRange],
+    /// Position of the first address of each range.
+    starts: Vec<u128>,
+}
+
+impl Indexed<'_> {
+    /// The address `iter().nth(index)` would yield, found without walking
+    /// the ranges before it.
+    pub fn get(&self, index: u128) -> Option<IpAddr> {
+        let position = self
+            .starts
+            .partition_point(|&start| start <= index)
+            .checked_sub(1)?;
+        let range = self.ranges[position];
+        let offset = index - self.starts[position];
+        (offset < range.len()).then(|| to_ip(range.v6, range.start + offset))
+    }
+}
+
+impl<'s> IntoIterator for &'s TargetSet {
+    type Item = IpAddr;
+    type IntoIter = Iter<'s>;
+
+    fn into_iter(self) -> Iter<'s> {
+        self.iter()
+    }
+}
+
+impl<T: AsRef<[IpAddr]> + ?Sized> From<&T> for TargetSet {
+    fn from(ips: &T) -> Self {
+        ips.as_ref().iter().copied().collect()
+    }
+}
+
+impl FromIterator<IpAddr> for TargetSet {
+    fn from_iter<I: IntoIterator<Item = IpAddr>>(ips: I) -> Self {
+        let mut targets = TargetSet::new();
+        targets.extend(ips);
+        targets
+    }
+}
+
+impl Extend<IpAddr> for TargetSet {
+    fn extend<I: IntoIterator<Item = IpAddr>>(&mut self, ips: I) {
+        for ip in ips {
+            self.insert(ip);
+        }
+    }
+}
+
+/// Saved as a list of addresses and `start-end` ranges, which reads back
+/// a plain list of addresses too.
+impl Serialize for TargetSet {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.ranges.iter().map(ToString::to_string))
+    }
+}
+
+impl<'de> Deserialize<'de> for TargetSet {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mut targets = TargetSet::new();
+        for entry in Vec::<String>::deserialize(deserializer)? {
+            let (start, end) = entry.split_once('-').unwrap_or((&entry, &entry));
+            let parse = |ip: &str| IpAddr::from_str(ip).map(to_number);
+            let ((v6, start), (end_v6, end)) = parse(start)
+                .and_then(|start| Ok((start, parse(end)?)))
+                .map_err(de::Error::custom)?;
+            if v6 != end_v6 || start > end {
+                return Err(de::Error::custom(format!("invalid address range {entry}")));
+            }
+            targets.insert_range(IpRange { v6, start, end });
+        }
+        Ok(targets)
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{get_resolver, parse_addresses, Opts};
-    use std::net::Ipv4Addr;
+    use super::{get_resolver, parse_addresses, parse_targets, Opts, TargetSet};
+    use crate::input::ResolvePolicy;
+    use cidr_utils::cidr::IpCidr;
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+    use std::str::FromStr;
 
     #[test]
     fn parse_correct_addresses() {
@@ -207,7 +632,7 @@ mod tests {
             ..Default::default()
         };
 
-        let ips = parse_addresses(&opts);
+        let ips: Vec<IpAddr> = parse_addresses(&opts).iter().collect();
 
         assert_eq!(
             ips,
@@ -228,7 +653,7 @@ mod tests {
             exclude_addresses: Some(vec!["192.168.0.1".to_owned()]),
             ..Default::default()
         };
-        let ips = parse_addresses(&opts);
+        let ips: Vec<IpAddr> = parse_addresses(&opts).iter().collect();
 
         assert_eq!(
             ips,
@@ -247,7 +672,7 @@ mod tests {
             exclude_addresses: Some(vec!["192.168.0.0/30".to_owned()]),
             ..Default::default()
         };
-        let ips = parse_addresses(&opts);
+        let ips: Vec<IpAddr> = parse_addresses(&opts).iter().collect();
 
         assert_eq!(
             ips,
@@ -267,7 +692,7 @@ mod tests {
             exclude_addresses: Some(vec!["192.168.0.1".to_owned()]),
             ..Default::default()
         };
-        let ips = parse_addresses(&opts);
+        let ips: Vec<IpAddr> = parse_addresses(&opts).iter().collect();
 
         assert_eq!(
             ips,
@@ -279,18 +704,140 @@ mod tests {
         );
     }
 
+    #[test]
+    fn parse_huge_cidrs_lazily() {
+        let opts = Opts {
+            addresses: vec!["10.0.0.0/8".to_owned(), "2001:db8::/32".to_owned()],
+            exclude_addresses: Some(vec!["10.128.0.0/9".to_owned(), "2001:db8::/33".to_owned()]),
+            ..Default::default()
+        };
+        let ips = parse_addresses(&opts);
+
+        assert_eq!(ips.ipv4_len(), 1 << 23);
+        assert_eq!(ips.ipv6_len(), 1 << 95);
+        assert!(ips.contains("10.127.255.255".parse().unwrap()));
+        assert!(!ips.contains("10.128.0.0".parse().unwrap()));
+        assert_eq!(
+            ips.iter().nth(1 << 23),
+            Some("2001:db8:8000::".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn target_set_keeps_insertion_order_without_duplicates() {
+        let mut targets = TargetSet::new();
+        targets.insert("10.0.0.2".parse().unwrap());
+        targets.insert_cidr(&IpCidr::from_str("10.0.0.0/30").unwrap());
+        targets.insert("::1".parse().unwrap());
+        targets.insert("10.0.0.1".parse().unwrap());
+
+        let ips: Vec<IpAddr> = targets.iter().collect();
+        assert_eq!(
+            ips,
+            ["10.0.0.2", "10.0.0.0", "10.0.0.1", "10.0.0.3", "::1"]
+                .map(|ip| ip.parse::<IpAddr>().unwrap())
+        );
+        assert_eq!(targets.len(), 5);
+    }
+
+    #[test]
+    fn target_set_removal_splits_ranges() {
+        let mut targets = TargetSet::new();
+        targets.insert_cidr(&IpCidr::from_str("192.168.0.0/29").unwrap());
+        targets.remove_all(&TargetSet::from(&[
+            "192.168.0.2".parse::<IpAddr>().unwrap(),
+            "192.168.0.5".parse().unwrap(),
+            "::1".parse().unwrap(),
+        ]));
+
+        let ips: Vec<IpAddr> = targets.iter().collect();
+        assert_eq!(
+            ips,
+            [0, 1, 3, 4, 6, 7].map(|last| IpAddr::V4(Ipv4Addr::new(192, 168, 0, last)))
+        );
+        // Removed addresses can be added back.
+        targets.insert("192.168.0.5".parse().unwrap());
+        assert_eq!(targets.len(), 7);
+    }
+
+    #[test]
+    fn target_set_gets_addresses_by_position() {
+        let mut targets = TargetSet::new();
+        targets.insert_cidr(&IpCidr::from_str("10.0.0.0/30").unwrap());
+        targets.insert("::1".parse().unwrap());
+        targets.insert_cidr(&IpCidr::from_str("192.168.0.0/16").unwrap());
+        let indexed = targets.indexed();
+
+        for (index, ip) in targets.iter().enumerate() {
+            assert_eq!(indexed.get(index as u128), Some(ip));
+        }
+        assert_eq!(indexed.get(targets.len()), None);
+        assert_eq!(TargetSet::new().indexed().get(0), None);
+    }
+
+    #[test]
+    fn target_set_length_saturates() {
+        let mut targets = TargetSet::new();
+        targets.insert_cidr(&IpCidr::from_str("::/0").unwrap());
+        targets.insert_cidr(&IpCidr::from_str("0.0.0.0/0").unwrap());
+
+        assert_eq!(targets.len(), u128::MAX);
+        assert_eq!(targets.ipv4_len(), 1 << 32);
+        assert_eq!(targets.iter().next(), Some("::".parse().unwrap()));
+    }
+
     #[test]
     fn parse_correct_host_addresses() {
         let opts = Opts {
             addresses: vec!["google.com".to_owned()],
+            resolve: ResolvePolicy::First,
             ..Default::default()
         };
 
-        let ips = parse_addresses(&opts);
+        let ips: Vec<IpAddr> = parse_addresses(&opts).iter().collect();
 
         assert_eq!(ips.len(), 1);
     }
 
+    #[test]
+    fn parse_host_addresses_with_resolve_policy() {
+        let v4 = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let v6 = IpAddr::V6(Ipv6Addr::LOCALHOST);
+        for (resolve, expected) in [
+            (ResolvePolicy::All, vec![v4, v6]),
+            (ResolvePolicy::First, vec![v4]),
+            (ResolvePolicy::V4, vec![v4]),
+            (ResolvePolicy::V6, vec![v6]),
+        ] {
+            let opts = Opts {
+                addresses: vec!["localhost".to_owned()],
+                resolve,
+                ..Default::default()
+            };
+
+            assert_eq!(parse_addresses(&opts).iter().collect::<Vec<_>>(), expected);
+        }
+    }
+
+    #[test]
+    fn resolved_addresses_keep_their_hostname() {
+        let opts = Opts {
+            addresses: vec!["localhost".to_owned(), "127.0.0.2".to_owned()],
+            resolve: ResolvePolicy::V4,
+            ..Default::default()
+        };
+
+        let targets = parse_targets(&opts);
+        let ips: Vec<IpAddr> = targets.ips.iter().collect();
+
+        assert_eq!(
+            ips,
+            [Ipv4Addr::new(127, 0, 0, 1), Ipv4Addr::new(127, 0, 0, 2)]
+        );
+        assert_eq!(targets.hostnames(ips[0]), ["localhost"]);
+        assert!(targets.hostnames(ips[1]).is_empty());
+    }
+
     #[test]
     fn parse_correct_and_incorrect_addresses() {
         let opts = Opts {
@@ -298,7 +845,7 @@ mod tests {
             ..Default::default()
         };
 
-        let ips = parse_addresses(&opts);
+        let ips: Vec<IpAddr> = parse_addresses(&opts).iter().collect();
 
         assert_eq!(ips, [Ipv4Addr::new(127, 0, 0, 1),]);
     }
@@ -310,7 +857,7 @@ mod tests {
             ..Default::default()
         };
 
-        let ips = parse_addresses(&opts);
+        let ips: Vec<IpAddr> = parse_addresses(&opts).iter().collect();
 
         assert!(ips.is_empty());
     }
@@ -320,10 +867,11 @@ mod tests {
         // Host file contains IP, Hosts, incorrect IPs, incorrect hosts
         let opts = Opts {
             addresses: vec!["fixtures/hosts.txt".to_owned()],
+            resolve: ResolvePolicy::First,
             ..Default::default()
         };
 
-        let ips = parse_addresses(&opts);
+        let ips: Vec<IpAddr> = parse_addresses(&opts).iter().collect();
 
         assert_eq!(ips.len(), 3);
     }
@@ -336,7 +884,7 @@ mod tests {
             ..Default::default()
         };
 
-        let ips = parse_addresses(&opts);
+        let ips: Vec<IpAddr> = parse_addresses(&opts).iter().collect();
 
         assert_eq!(ips.len(), 0);
     }
@@ -349,7 +897,7 @@ mod tests {
             ..Default::default()
         };
 
-        let ips = parse_addresses(&opts);
+        let ips: Vec<IpAddr> = parse_addresses(&opts).iter().collect();
 
         assert_eq!(ips.len(), 0);
     }
@@ -361,7 +909,7 @@ mod tests {
             ..Default::default()
         };
 
-        let ips = parse_addresses(&opts);
+        let ips: Vec<IpAddr> = parse_addresses(&opts).iter().collect();
 
         assert_eq!(ips.len(), 2_048);
     }
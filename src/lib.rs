@@ -4,8 +4,9 @@
 //! ## Example: perform a scan against localhost
 //!
 //! The core scanning behaviour is managed by
-//! [`Scanner`](crate::scanner::Scanner) which in turn requires a
-//! [`PortStrategy`](crate::port_strategy::PortStrategy):
+//! [`Scanner`](crate::scanner::Scanner), built from a
+//! [`PortStrategy`](crate::port_strategy::PortStrategy) by a
+//! [`ScannerBuilder`](crate::scanner::ScannerBuilder):
 //!
 //! ```rust
 //! use async_std::task::block_on;
@@ -13,7 +14,7 @@
 //!
 //! use rustscan::input::{PortRange, ScanOrder};
 //! use rustscan::port_strategy::PortStrategy;
-//! use rustscan::scanner::Scanner;
+//! use rustscan::scanner::{Protocol, Scanner};
 //!
 //! fn main() {
 //!     let addrs = vec!["127.0.0.1".parse::<IpAddr>().unwrap()];
@@ -22,21 +23,18 @@
 //!         end: 1_000,
 //!     };
 //!     let strategy = PortStrategy::pick(&Some(range), None, ScanOrder::Random); // can be serial, random or manual https://github.com/RustScan/RustScan/blob/master/src/port_strategy/mod.rs
-//!     let scanner = Scanner::new(
-//!         &addrs, // the addresses to scan
-//!         10, // batch_size is how many ports at a time should be scanned
-//!         Duration::from_millis(100), //T imeout is the time RustScan should wait before declaring a port closed. As datatype Duration.
-//!         1, // Tries, how many retries should RustScan do?
-//!         true, // greppable is whether or not RustScan should print things, or wait until the end to print only the ip
-//!         strategy, // the port strategy used
-//!         true, // accessible, should the output be A11Y compliant?
-//!         vec![9000], // What ports should RustScan exclude?
-//!         false, // is this a UDP scan?
-//!     );
+//!     // Every option left out of the builder keeps its default.
+//!     let scanner = Scanner::builder(&addrs, strategy)
+//!         .batch_size(10) // how many ports at a time should be scanned
+//!         .timeout(Duration::from_millis(100)) // how long to wait before declaring a port closed
+//!         .tries(1) // how many retries should RustScan do?
+//!         .exclude_ports(vec![9000]) // What ports should RustScan exclude?
+//!         .build()
+//!         .expect("the options go together");
 //!
 //!     let scan_result = block_on(scanner.run());
 //!
-//!     println!("{:?}", scan_result);
+//!     println!("{:?}", scan_result.open_sockets(Protocol::Tcp));
 //! }
 //! ```
 #![allow(clippy::needless_doctest_main)]
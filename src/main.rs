@@ -5,18 +5,23 @@
 use rustscan::benchmark::{Benchmark, NamedTimer};
 use rustscan::input::{self, Config, Opts, ScriptsRequired};
 use rustscan::port_strategy::PortStrategy;
-use rustscan::scanner::Scanner;
-use rustscan::scripts::{init_scripts, Script, ScriptFile};
+use rustscan::scanner::{
+    Banner, BannerGrab, Checkpoint, Checkpointing, HostDiscovery, Protocol, ProxyChain,
+    RateLimiter, RttTimeouts, ScanEvent, ScanReport, Scanner, SourceBinding, SynScanner,
+    TimeBudget,
+};
+use rustscan::scripts::{init_scripts, OpenPorts, Script, ScriptFile};
 use rustscan::{detail, funny_opening, output, warning};
 
 use colorful::{Color, Colorful};
 use futures::executor::block_on;
+use futures::StreamExt;
 use std::collections::HashMap;
-use std::net::IpAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::string::ToString;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime};
 
-use rustscan::address::parse_addresses;
+use rustscan::address::{parse_targets, Targets};
 
 extern crate colorful;
 extern crate dirs;
@@ -26,6 +31,14 @@ extern crate dirs;
 const DEFAULT_FILE_DESCRIPTORS_LIMIT: u64 = 8000;
 // Safest batch size based on experimentation
 const AVERAGE_BATCH_SIZE: u16 = 3000;
+// Lowest per-host timeout in milliseconds when only the highest is given
+const DEFAULT_MIN_RTT_TIMEOUT: u32 = 100;
+// How often the progress of the scan is saved with --resume-file
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(5);
+// Ranges with more hosts than this are scanned without discovery first
+const MAX_DISCOVERY_HOSTS: u128 = 65_536;
+// Certificates expiring sooner than this get a warning
+const CERTIFICATE_EXPIRY_WARNING_DAYS: u64 = 30;
 
 #[macro_use]
 extern crate log;
@@ -43,6 +56,30 @@ fn main() {
     let mut rustscan_bench = NamedTimer::start("RustScan");
 
     let mut opts: Opts = Opts::read();
+
+    // A resumed scan runs again with the command line it was started with.
+    let resumed = opts
+        .resume
+        .clone()
+        .map(|path| match Checkpoint::read(&path) {
+            Ok(checkpoint) => {
+                opts = Opts::read_from(&checkpoint.args);
+                opts.resume_file = Some(path);
+                checkpoint
+            }
+            Err(e) => {
+                warning!(
+                    format!(
+                        "Could not resume the scan saved in {}.\n{e}",
+                        path.display()
+                    ),
+                    opts.greppable,
+                    opts.accessible
+                );
+                std::process::exit(1);
+            }
+        });
+
     let config = Config::read(opts.config_path.clone());
     opts.merge(&config);
 
@@ -66,7 +103,12 @@ fn main() {
         print_opening(&opts);
     }
 
-    let ips: Vec<IpAddr> = parse_addresses(&opts);
+    // A resumed scan only knows its IPs, not the hostnames they came from.
+    let targets = match &resumed {
+        Some(checkpoint) => Targets::from(checkpoint.ips.clone()),
+        None => parse_targets(&opts),
+    };
+    let ips = targets.ips.clone();
 
     if ips.is_empty() {
         warning!(
@@ -77,49 +119,394 @@ fn main() {
         std::process::exit(1);
     }
 
+    let source = SourceBinding {
+        ip: opts.source_ip,
+        interface: opts.interface.clone(),
+        port: opts.source_port,
+    };
+
+    if let Some(source_ip) = source.ip {
+        let unreachable = if source_ip.is_ipv4() {
+            ips.ipv6_len()
+        } else {
+            ips.ipv4_len()
+        };
+        if unreachable > 0 {
+            warning!(
+                format!("{unreachable} host(s) can't be reached from source address {source_ip} and will show no open ports."),
+                opts.greppable,
+                opts.accessible
+            );
+        }
+    }
+
+    let syn = if opts.syn {
+        match SynScanner::new(&source) {
+            Ok(syn) => Some(syn),
+            Err(e) => {
+                warning!(
+                    format!(
+                        "Could not start a SYN scan, it needs Linux and root or CAP_NET_RAW.\n{e}"
+                    ),
+                    opts.greppable,
+                    opts.accessible
+                );
+                std::process::exit(1);
+            }
+        }
+    } else {
+        None
+    };
+
+    // SYN probes don't hold a file descriptor, the file limit does not apply.
     #[cfg(unix)]
-    let batch_size: u16 = infer_batch_size(&opts, adjust_ulimit_size(&opts));
+    let batch_size: u16 = if syn.is_some() {
+        opts.batch_size
+    } else {
+        infer_batch_size(&opts, adjust_ulimit_size(&opts))
+    };
 
     #[cfg(not(unix))]
     let batch_size: u16 = AVERAGE_BATCH_SIZE;
 
-    let scanner = Scanner::new(
-        &ips,
-        batch_size,
-        Duration::from_millis(opts.timeout.into()),
-        opts.tries,
-        opts.greppable,
-        PortStrategy::pick(&opts.range, opts.ports, opts.scan_order),
-        opts.accessible,
-        opts.exclude_ports.unwrap_or_default(),
-        opts.udp,
-    );
+    let proxy = if opts.proxy.is_empty() {
+        None
+    } else {
+        match ProxyChain::new(opts.proxy.clone()) {
+            Ok(proxy) => Some(proxy),
+            Err(e) => {
+                warning!(e, opts.greppable, opts.accessible);
+                std::process::exit(1);
+            }
+        }
+    };
+    let batch_size = if proxy.is_some() {
+        batch_size.min(opts.proxy_batch_size.max(1))
+    } else {
+        batch_size
+    };
+
+    let rate_limiter = RateLimiter::new(opts.max_rate, opts.max_rate_per_host);
+
+    // Pings can't go through a proxy, every host is assumed up. The hosts of
+    // a resumed scan already went through discovery.
+    let ips = if opts.skip_discovery || proxy.is_some() || resumed.is_some() {
+        ips
+    } else if ips.len() > MAX_DISCOVERY_HOSTS {
+        warning!(
+            format!("{} hosts are too many to ping first, every one of them will be scanned. Use '--skip-discovery' to hide this warning.", ips.len()),
+            opts.greppable,
+            opts.accessible
+        );
+        ips
+    } else {
+        let mut discovery_bench = NamedTimer::start("Discovery");
+        // TCP pings always hold a file descriptor, even during SYN scans.
+        let discovery = HostDiscovery::new(
+            opts.ping_ports.clone(),
+            Duration::from_millis(opts.timeout.into()),
+            batch_size.min(AVERAGE_BATCH_SIZE),
+            source.clone(),
+            rate_limiter.clone(),
+        );
+        let discovery_result = block_on(discovery.run(&ips));
+        discovery_bench.end();
+        benchmarks.push(discovery_bench);
+
+        // How every host found up answered, ahead of the port scan output.
+        for (ip, status) in discovery_result.statuses() {
+            detail!(
+                format!("Host {ip} is {status}"),
+                opts.greppable,
+                opts.accessible
+            );
+        }
+        let down = discovery_result.down();
+        if down > 0 {
+            warning!(
+                format!("{down} host(s) did not answer any ping and will not be scanned, use '--skip-discovery' to scan them anyway."),
+                opts.greppable,
+                opts.accessible
+            );
+        }
+
+        // The hosts left keep the order they were given in.
+        ips.iter()
+            .filter(|ip| discovery_result.status(*ip).is_some())
+            .collect()
+    };
+
+    let seed = resumed
+        .as_ref()
+        .map_or_else(rand::random, |checkpoint| checkpoint.seed);
+    let checkpoint = opts.resume_file.clone().map(|path| Checkpointing {
+        path,
+        interval: CHECKPOINT_INTERVAL,
+        state: resumed.unwrap_or_else(|| Checkpoint {
+            args: std::env::args_os()
+                .map(|arg| arg.to_string_lossy().into_owned())
+                .collect(),
+            seed,
+            ips: ips.clone(),
+            position: 0,
+            open: vec![],
+            open_udp: vec![],
+        }),
+    });
+
+    let rtt_timeouts = rtt_timeouts(&opts);
+    let scanner = Scanner::builder(
+        ips,
+        PortStrategy::seeded(&opts.range, opts.ports, opts.scan_order, seed.into()),
+    )
+    .batch_size(batch_size)
+    .timeout(Duration::from_millis(opts.timeout.into()))
+    .tries(opts.tries)
+    .exclude_ports(opts.exclude_ports.unwrap_or_default())
+    .udp(opts.udp)
+    .min_batch_size(opts.adaptive.then_some(opts.min_batch_size))
+    .rate_limiter(rate_limiter)
+    .banner(opts.banner.then_some(BannerGrab {
+        wait: Duration::from_millis(opts.banner_timeout.into()),
+        probe: opts.banner_probe,
+    }))
+    .syn(syn)
+    .rtt_timeouts(rtt_timeouts)
+    .source(source)
+    .proxy(proxy)
+    .checkpoint(checkpoint)
+    .budget(TimeBudget {
+        host: opts.host_timeout.map(Duration::from_secs),
+        scan: opts.max_scan_time.map(Duration::from_secs),
+    })
+    .udp_ports(opts.udp_ports.clone())
+    .build();
+    let scanner = match scanner {
+        Ok(scanner) => scanner,
+        Err(e) => {
+            warning!(e, opts.greppable, opts.accessible);
+            std::process::exit(1);
+        }
+    };
     debug!("Scanner finished building: {:?}", scanner);
 
+    let cancel = scanner.cancel_handle();
+    let (greppable, accessible) = (opts.greppable, opts.accessible);
+    if let Err(e) = ctrlc::set_handler(move || {
+        if cancel.is_cancelled() {
+            std::process::exit(130);
+        }
+        warning!(
+            "Interrupted, waiting for the sockets in flight. Interrupt again to quit right away.",
+            greppable,
+            accessible
+        );
+        cancel.cancel();
+    }) {
+        debug!("Could not handle interrupts {}", e);
+    }
+
     let mut portscan_bench = NamedTimer::start("Portscan");
-    let scan_result = block_on(scanner.run());
+    let scan_started = Instant::now();
+    // Open ports are shown as soon as they are found, their banner comes
+    // right before them. Only open ports are printed one by one, the others
+    // are counted so a large scan doesn't keep every one of them around.
+    let mut scan_result = ScanReport::summary_only();
+    let both_protocols = !opts.udp_ports.is_empty();
+    block_on(async {
+        let mut events = Box::pin(scanner.stream());
+        while let Some(event) = events.next().await {
+            scan_result.record(&event);
+            if let ScanEvent::Open(socket, protocol) = event {
+                if !greppable {
+                    print_open(
+                        socket,
+                        both_protocols.then_some(protocol),
+                        scan_result.banner(socket),
+                        accessible,
+                    );
+                }
+            }
+        }
+    });
     portscan_bench.end();
     benchmarks.push(portscan_bench);
 
-    let mut ports_per_ip = HashMap::new();
+    if let Some(max_sockets) = scan_result.out_of_files() {
+        warning!(
+            format!("Ran out of open files, the scan went on with at most {max_sockets} sockets at a time. Lower the batch size or raise the file limit with '--ulimit' to avoid it."),
+            opts.greppable,
+            opts.accessible
+        );
+    }
 
-    for socket in scan_result {
-        ports_per_ip
-            .entry(socket.ip())
-            .or_insert_with(Vec::new)
-            .push(socket.port());
+    for ip in scan_result.incomplete_hosts() {
+        warning!(
+            format!("Not every port of {ip} was scanned, its results are incomplete."),
+            opts.greppable,
+            opts.accessible
+        );
+    }
+    let not_reached = scan_result.hosts_not_reached();
+    if not_reached > 0 {
+        warning!(
+            format!(
+                "The scan stopped before reaching {not_reached} host(s), they were not scanned."
+            ),
+            opts.greppable,
+            opts.accessible
+        );
     }
+    let interrupted = scanner.cancel_handle().is_cancelled();
+    if interrupted {
+        warning!(
+            "The scan was interrupted, only the ports found so far are shown.",
+            opts.greppable,
+            opts.accessible
+        );
+    }
+
+    // Service detection and TLS probing would only go further over the time
+    // the scan was given.
+    let out_of_time = !interrupted
+        && opts
+            .max_scan_time
+            .is_some_and(|max| scan_started.elapsed() >= Duration::from_secs(max));
+    if out_of_time {
+        warning!(
+            "The scan ran out of time, only the ports found so far are shown.",
+            opts.greppable,
+            opts.accessible
+        );
+    }
+
+    if opts.service_detect && !interrupted && !out_of_time {
+        if opts.udp {
+            warning!(
+                "Service detection only runs on TCP ports, skipping it.",
+                opts.greppable,
+                opts.accessible
+            );
+        } else {
+            let mut service_bench = NamedTimer::start("Services");
+            block_on(scanner.detect_services(&mut scan_result));
+            service_bench.end();
+            benchmarks.push(service_bench);
+
+            for (socket, service) in scan_result.services() {
+                detail!(
+                    format!("{socket} -> {service}"),
+                    opts.greppable,
+                    opts.accessible
+                );
+            }
+        }
+    }
+
+    if opts.tls && !interrupted && !out_of_time {
+        if opts.udp {
+            warning!(
+                "TLS probing only runs on TCP ports, skipping it.",
+                opts.greppable,
+                opts.accessible
+            );
+        } else {
+            let mut tls_bench = NamedTimer::start("TLS");
+            block_on(scanner.probe_tls(&mut scan_result, &targets));
+            tls_bench.end();
+            benchmarks.push(tls_bench);
+
+            let now = SystemTime::now();
+            let expiry_warning = Duration::from_secs(CERTIFICATE_EXPIRY_WARNING_DAYS * 86_400);
+            for (socket, tls) in scan_result.tls_sockets() {
+                detail!(
+                    format!("{socket} -> {tls}"),
+                    opts.greppable,
+                    opts.accessible
+                );
+
+                let Some(certificate) = &tls.certificate else {
+                    continue;
+                };
+                if certificate.expires_within(now, expiry_warning) {
+                    warning!(
+                        format!(
+                            "The certificate of {socket} is expired or expires within {CERTIFICATE_EXPIRY_WARNING_DAYS} days."
+                        ),
+                        opts.greppable,
+                        opts.accessible
+                    );
+                }
+                if certificate.self_signed {
+                    warning!(
+                        format!("The certificate of {socket} is self-signed."),
+                        opts.greppable,
+                        opts.accessible
+                    );
+                }
+            }
+        }
+    }
+
+    // The TCP and UDP ports found open on every host.
+    let mut ports_per_ip: HashMap<IpAddr, OpenPorts> = HashMap::new();
 
-    for ip in ips {
-        if ports_per_ip.contains_key(&ip) {
+    for protocol in [Protocol::Tcp, Protocol::Udp] {
+        for socket in scan_result.open_sockets(protocol) {
+            let ports = ports_per_ip.entry(socket.ip()).or_default();
+            match protocol {
+                Protocol::Tcp => ports.tcp.push(socket.port()),
+                Protocol::Udp => ports.udp.push(socket.port()),
+            }
+        }
+    }
+
+    // Only the hosts the scan got to have a summary, a huge range never
+    // goes through every one of its addresses here.
+    let scanned_ips: Vec<IpAddr> = scan_result.hosts().map(|(ip, _)| *ip).collect();
+    for ip in scanned_ips {
+        let summary = scan_result.summary(ip);
+        let hostnames = targets.hostnames(ip);
+        let host = if hostnames.is_empty() {
+            ip.to_string()
+        } else {
+            format!("{ip} ({})", hostnames.join(", "))
+        };
+        detail!(
+            format!("{host} -> {summary}"),
+            opts.greppable,
+            opts.accessible
+        );
+
+        if ports_per_ip.contains_key(&ip) || interrupted || scan_result.is_incomplete(ip) {
             continue;
         }
 
         // If we got here it means the IP was not found within the HashMap, this
         // means the scan couldn't find any open ports for it.
 
+        // A host refusing every connection is up, it just has nothing listening.
+        if summary.total() > 0 && summary.closed == summary.total() {
+            warning!(
+                format!("Every port of {ip} refused the connection, the host is up but nothing is listening."),
+                opts.greppable,
+                opts.accessible
+            );
+            continue;
+        }
+
+        // UDP services ignoring the probes can't be told from a firewall dropping them.
+        if summary.open_filtered > 0 {
+            warning!(
+                format!("No UDP port of {ip} answered, {} of them are open|filtered: either nothing understood the probes or a firewall dropped them.", summary.open_filtered),
+                opts.greppable,
+                opts.accessible
+            );
+            continue;
+        }
+
         let x = format!("Looks like I didn't find any open ports for {:?}. This is usually caused by a high batch size.
-        \n*I used {} batch size, consider lowering it with {} or a comfortable number for your system.
+        \n*I used {} batch size, consider lowering it with {} or a comfortable number for your system, or let RustScan adapt it with '--adaptive'.
         \n Alternatively, increase the timeout if your ping is high. Rustscan -t 2000 for 2000 milliseconds (2s) timeout.\n",
         ip,
         opts.batch_size,
@@ -129,13 +516,31 @@ fn main() {
 
     let mut script_bench = NamedTimer::start("Scripts");
     for (ip, ports) in &ports_per_ip {
-        let vec_str_ports: Vec<String> = ports.iter().map(ToString::to_string).collect();
-
-        // nmap port style is 80,443. Comma separated with no spaces.
-        let ports_str = vec_str_ports.join(",");
+        // nmap port style is 80,443. Comma separated with no spaces. Ports are
+        // tagged with their protocol when the scan covered both.
+        let ports_str = if opts.udp_ports.is_empty() {
+            ports
+                .tcp
+                .iter()
+                .chain(&ports.udp)
+                .map(ToString::to_string)
+                .collect::<Vec<String>>()
+                .join(",")
+        } else {
+            ports
+                .tcp
+                .iter()
+                .map(|port| format!("{port}/tcp"))
+                .chain(ports.udp.iter().map(|port| format!("{port}/udp")))
+                .collect::<Vec<String>>()
+                .join(",")
+        };
 
         // if option scripts is none, no script will be spawned
-        if opts.greppable || opts.scripts == ScriptsRequired::None {
+        if opts.greppable
+            || opts.scripts == ScriptsRequired::None
+            || (interrupted && !opts.scripts_on_interrupt)
+        {
             println!("{} -> [{}]", &ip, ports_str);
             continue;
         }
@@ -173,7 +578,7 @@ fn main() {
             );
             match script.run() {
                 Ok(script_result) => {
-                    detail!(script_result.to_string(), opts.greppable, opts.accessible);
+                    detail!(script_result, opts.greppable, opts.accessible);
                 }
                 Err(e) => {
                     warning!(&format!("Error {e}"), opts.greppable, opts.accessible);
@@ -189,6 +594,11 @@ fn main() {
     benchmarks.push(rustscan_bench);
     debug!("Benchmarks raw {:?}", benchmarks);
     info!("{}", benchmarks.summary());
+
+    // Like a shell killed by SIGINT, so callers know the results are partial.
+    if interrupted {
+        std::process::exit(130);
+    }
 }
 
 /// Prints the opening title of RustScan
@@ -221,6 +631,42 @@ The Modern Day Port Scanner."#;
     );
 }
 
+/// Prints an open port, followed by its banner if one was grabbed. The
+/// protocol is only given when the scan covers both TCP and UDP ports.
+fn print_open(
+    socket: SocketAddr,
+    protocol: Option<Protocol>,
+    banner: Option<&Banner>,
+    accessible: bool,
+) {
+    let protocol = protocol.map(|p| format!("/{p}")).unwrap_or_default();
+    let banner = banner.map(|b| format!(" [{b}]")).unwrap_or_default();
+    if accessible {
+        println!("Open {socket}{protocol}{banner}");
+    } else {
+        println!(
+            "Open {}{protocol}{banner}",
+            ansi_term::Colour::Purple.paint(socket.to_string())
+        );
+    }
+}
+
+/// The bounds of per-host timeouts, when either of them was given.
+fn rtt_timeouts(opts: &Opts) -> Option<RttTimeouts> {
+    if opts.min_rtt_timeout.is_none() && opts.max_rtt_timeout.is_none() {
+        return None;
+    }
+
+    Some(RttTimeouts {
+        min: Duration::from_millis(
+            opts.min_rtt_timeout
+                .unwrap_or(DEFAULT_MIN_RTT_TIMEOUT)
+                .into(),
+        ),
+        max: Duration::from_millis(opts.max_rtt_timeout.unwrap_or(opts.timeout).into()),
+    })
+}
+
 #[cfg(unix)]
 fn adjust_ulimit_size(opts: &Opts) -> u64 {
     use rlimit::Resource;
@@ -1,8 +1,9 @@
 //! Provides a means to hold configuration options specifically for port scanning.
 mod range_iterator;
 use crate::input::{PortRange, ScanOrder};
-use rand::rng;
+use rand::rngs::StdRng;
 use rand::seq::SliceRandom;
+use rand::SeedableRng;
 use range_iterator::RangeIterator;
 
 /// Represents options of port scanning.
@@ -14,10 +15,22 @@ pub enum PortStrategy {
     Manual(Vec<u16>),
     Serial(SerialRange),
     Random(RandomRange),
+    Permuted(PermutedProduct),
 }
 
 impl PortStrategy {
     pub fn pick(range: &Option<PortRange>, ports: Option<Vec<u16>>, order: ScanOrder) -> Self {
+        Self::seeded(range, ports, order, rand::random())
+    }
+
+    /// Same as `pick`, with the random order derived from `seed`. The same
+    /// seed always gives the same order, which lets a scan be resumed.
+    pub fn seeded(
+        range: &Option<PortRange>,
+        ports: Option<Vec<u16>>,
+        order: ScanOrder,
+        seed: u64,
+    ) -> Self {
         match order {
             ScanOrder::Serial if ports.is_none() => {
                 let range = range.as_ref().unwrap();
@@ -31,11 +44,19 @@ impl PortStrategy {
                 PortStrategy::Random(RandomRange {
                     start: range.start,
                     end: range.end,
+                    seed,
                 })
             }
+            ScanOrder::Permuted => PortStrategy::Permuted(PermutedProduct {
+                ports: ports.unwrap_or_else(|| {
+                    let range = range.as_ref().unwrap();
+                    (range.start..=range.end).collect()
+                }),
+                seed,
+            }),
             ScanOrder::Serial => PortStrategy::Manual(ports.unwrap()),
             ScanOrder::Random => {
-                let mut rng = rng();
+                let mut rng = StdRng::seed_from_u64(seed);
                 let mut ports = ports.unwrap();
                 ports.shuffle(&mut rng);
                 PortStrategy::Manual(ports)
@@ -48,6 +69,16 @@ impl PortStrategy {
             PortStrategy::Manual(ports) => ports.clone(),
             PortStrategy::Serial(range) => range.generate(),
             PortStrategy::Random(range) => range.generate(),
+            PortStrategy::Permuted(product) => product.generate(),
+        }
+    }
+
+    /// The seed the host and port pairs are shuffled with, when the order
+    /// is not left to the ports alone.
+    pub fn product_seed(&self) -> Option<u64> {
+        match self {
+            PortStrategy::Permuted(product) => Some(product.seed),
+            _ => None,
         }
     }
 }
@@ -78,6 +109,7 @@ impl RangeOrder for SerialRange {
 pub struct RandomRange {
     start: u16,
     end: u16,
+    seed: u64,
 }
 
 impl RangeOrder for RandomRange {
@@ -91,7 +123,21 @@ impl RangeOrder for RandomRange {
     // port numbers close to each other are pretty slim due to the way the
     // algorithm works.
     fn generate(&self) -> Vec<u16> {
-        RangeIterator::new(self.start.into(), self.end.into()).collect()
+        RangeIterator::new(self.start.into(), self.end.into(), self.seed).collect()
+    }
+}
+
+/// PermutedProduct keeps the ports as given, the scanner shuffles every
+/// host and port pair with its seed instead.
+#[derive(Debug)]
+pub struct PermutedProduct {
+    ports: Vec<u16>,
+    seed: u64,
+}
+
+impl RangeOrder for PermutedProduct {
+    fn generate(&self) -> Vec<u16> {
+        self.ports.clone()
     }
 }
 
@@ -120,6 +166,34 @@ mod tests {
         assert_eq!(expected_range, result);
     }
 
+    #[test]
+    fn seeded_strategy_repeats_its_order() {
+        let range = Some(PortRange { start: 1, end: 100 });
+        let first = PortStrategy::seeded(&range, None, ScanOrder::Random, 7).order();
+        let second = PortStrategy::seeded(&range, None, ScanOrder::Random, 7).order();
+        assert_eq!(first, second);
+
+        let ports = Some((1..10).collect::<Vec<u16>>());
+        let first = PortStrategy::seeded(&None, ports.clone(), ScanOrder::Random, 7).order();
+        let second = PortStrategy::seeded(&None, ports, ScanOrder::Random, 7).order();
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn permuted_strategy_leaves_the_order_to_the_scanner() {
+        let range = Some(PortRange { start: 1, end: 100 });
+        let strategy = PortStrategy::seeded(&range, None, ScanOrder::Permuted, 7);
+        assert_eq!(strategy.order(), (1..=100).collect::<Vec<u16>>());
+        assert_eq!(strategy.product_seed(), Some(7));
+
+        let strategy = PortStrategy::seeded(&None, Some(vec![443, 80]), ScanOrder::Permuted, 7);
+        assert_eq!(strategy.order(), vec![443, 80]);
+        assert_eq!(
+            PortStrategy::seeded(&range, None, ScanOrder::Random, 7).product_seed(),
+            None
+        );
+    }
+
     #[test]
     fn serial_strategy_with_ports() {
         let strategy = PortStrategy::pick(&None, Some(vec![80, 443]), ScanOrder::Serial);
@@ -0,0 +1,182 @@
+//! Reads the first bytes a service sends once its port is found open.
+use async_std::io::{self, Read, Write};
+use async_std::prelude::*;
+use log::debug;
+use std::fmt;
+use std::time::Duration;
+
+/// Most banners (SSH, SMTP, FTP greetings) fit in a single small read.
+const BANNER_BUFFER_SIZE: usize = 1024;
+
+/// Sent when the service stays silent. Blank lines are understood, or at
+/// least answered with an error, by most line based protocols.
+const GENERIC_PROBE: &[u8] = b"\r\n\r\n";
+
+/// How to grab banners on open TCP ports.
+/// wait is how long to wait for the service to talk, before and after the probe.
+/// probe is whether to send a small protocol-neutral probe when nothing was received.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BannerGrab {
+    pub wait: Duration,
+    pub probe: bool,
+}
+
+/// The bytes a service sent after the connection was established.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Banner {
+    pub bytes: Vec<u8>,
+    /// Whether the bytes only came after sending the generic probe.
+    pub probed: bool,
+}
+
+impl fmt::Display for Banner {
+    /// Prints the banner on a single line, escaping anything that is not
+    /// printable ASCII the way nmap does in its fingerprints.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let end = self
+            .bytes
+            .iter()
+            .rposition(|byte| !matches!(byte, b'\r' | b'\n'))
+            .map_or(0, |position| position + 1);
+
+        for byte in &self.bytes[..end] {
+            match byte {
+                b'\r' => write!(f, "\\r")?,
+                b'\n' => write!(f, "\\n")?,
+                b'\t' => write!(f, "\\t")?,
+                b'\\' => write!(f, "\\\\")?,
+                0x20..=0x7e => write!(f, "{}", char::from(*byte))?,
+                _ => write!(f, "\\x{byte:02x}")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Waits for the service behind `stream` to talk, sending the generic probe
+/// when it stays silent and `options.probe` is set.
+/// Returns None when nothing was received, or the connection failed.
+pub async fn grab(stream: &mut (impl Read + Write + Unpin), options: BannerGrab) -> Option<Banner> {
+    if let Some(bytes) = read_some(stream, options.wait).await {
+        return Some(Banner {
+            bytes,
+            probed: false,
+        });
+    }
+
+    if !options.probe {
+        return None;
+    }
+
+    if let Err(e) = stream.write_all(GENERIC_PROBE).await {
+        debug!("Failed to send banner probe {}", e);
+        return None;
+    }
+
+    read_some(stream, options.wait).await.map(|bytes| Banner {
+        bytes,
+        probed: true,
+    })
+}
+
+async fn read_some(stream: &mut (impl Read + Unpin), wait: Duration) -> Option<Vec<u8>> {
+    let mut buf = vec![0u8; BANNER_BUFFER_SIZE];
+    match io::timeout(wait, stream.read(&mut buf)).await {
+        Ok(0) => None,
+        Ok(size) => {
+            buf.truncate(size);
+            Some(buf)
+        }
+        Err(e) => {
+            debug!("No banner received {}", e);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{grab, Banner, BannerGrab};
+    use async_std::net::TcpStream;
+    use async_std::task::block_on;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+    use std::time::Duration;
+
+    const OPTIONS: BannerGrab = BannerGrab {
+        wait: Duration::from_millis(300),
+        probe: true,
+    };
+
+    #[test]
+    fn grabs_banner_sent_on_connect() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.write_all(b"SSH-2.0-OpenSSH_9.6\r\n").unwrap();
+        });
+
+        let banner = block_on(async {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            grab(&mut stream, OPTIONS).await
+        });
+
+        assert_eq!(
+            banner,
+            Some(Banner {
+                bytes: b"SSH-2.0-OpenSSH_9.6\r\n".to_vec(),
+                probed: false,
+            })
+        );
+    }
+
+    #[test]
+    fn probes_silent_services() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = [0u8; 16];
+            let _ = stream.read(&mut buf).unwrap();
+            stream.write_all(b"HTTP/1.1 400 Bad Request\r\n").unwrap();
+        });
+
+        let banner = block_on(async {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            grab(&mut stream, OPTIONS).await
+        })
+        .unwrap();
+
+        assert!(banner.probed);
+        assert_eq!(banner.to_string(), "HTTP/1.1 400 Bad Request");
+    }
+
+    #[test]
+    fn silent_service_without_probe_has_no_banner() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let banner = block_on(async {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            let options = BannerGrab {
+                probe: false,
+                ..OPTIONS
+            };
+            grab(&mut stream, options).await
+        });
+
+        drop(listener);
+        assert_eq!(banner, None);
+    }
+
+    #[test]
+    fn escapes_unprintable_bytes() {
+        let banner = Banner {
+            bytes: b"220 ready\r\n\x00\xff\tend\r\n".to_vec(),
+            probed: false,
+        };
+        assert_eq!(banner.to_string(), "220 ready\\r\\n\\x00\\xff\\tend");
+    }
+}
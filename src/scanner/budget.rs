@@ -0,0 +1,108 @@
+//! Time limits for a scan and for each of its hosts. Hosts still having
+//! sockets left when their time runs out are reported as incomplete.
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+/// How long a scan may take, as a whole and for each of its hosts. None
+/// leaves it unlimited.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct TimeBudget {
+    pub host: Option<Duration>,
+    pub scan: Option<Duration>,
+}
+
+/// Tracks the time spent on a scan and on each of its hosts, a host's clock
+/// starting with its first socket.
+#[derive(Debug)]
+pub struct BudgetClock {
+    budget: TimeBudget,
+    started: Instant,
+    hosts: HashMap<IpAddr, Instant>,
+}
+
+impl BudgetClock {
+    pub fn start(budget: TimeBudget) -> Self {
+        Self {
+            budget,
+            started: Instant::now(),
+            hosts: HashMap::new(),
+        }
+    }
+
+    /// When the whole scan runs out of time.
+    pub fn scan_deadline(&self) -> Option<Instant> {
+        self.budget.scan.map(|budget| self.started + budget)
+    }
+
+    /// Whether another socket of `ip` may still be scanned.
+    pub fn host_has_time(&mut self, ip: IpAddr) -> bool {
+        let Some(budget) = self.budget.host else {
+            return true;
+        };
+        let started = *self.hosts.entry(ip).or_insert_with(Instant::now);
+        started.elapsed() < budget
+    }
+
+    /// Stops the clock of `ip` once none of its sockets are left.
+    pub fn host_finished(&mut self, ip: IpAddr) {
+        self.hosts.remove(&ip);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BudgetClock, TimeBudget};
+    use std::net::IpAddr;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn unlimited_budget() {
+        let mut clock = BudgetClock::start(TimeBudget::default());
+        assert_eq!(clock.scan_deadline(), None);
+        assert!(clock.host_has_time("127.0.0.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn scan_deadline_follows_budget() {
+        let before = Instant::now();
+        let clock = BudgetClock::start(TimeBudget {
+            host: None,
+            scan: Some(Duration::from_secs(60)),
+        });
+        let deadline = clock.scan_deadline().unwrap();
+        assert!(deadline >= before + Duration::from_secs(60));
+        assert!(deadline <= Instant::now() + Duration::from_secs(60));
+    }
+
+    #[test]
+    fn host_clock_starts_with_its_first_socket() {
+        let mut clock = BudgetClock::start(TimeBudget {
+            host: Some(Duration::from_millis(50)),
+            scan: None,
+        });
+        let first = "10.0.0.1".parse::<IpAddr>().unwrap();
+        let second = "10.0.0.2".parse::<IpAddr>().unwrap();
+
+        assert!(clock.host_has_time(first));
+        thread::sleep(Duration::from_millis(60));
+
+        assert!(!clock.host_has_time(first));
+        assert!(clock.host_has_time(second));
+    }
+
+    #[test]
+    fn finished_hosts_are_forgotten() {
+        let mut clock = BudgetClock::start(TimeBudget {
+            host: Some(Duration::from_secs(60)),
+            scan: None,
+        });
+        for host in 0..100u8 {
+            let ip = IpAddr::from([10, 0, 0, host]);
+            assert!(clock.host_has_time(ip));
+            clock.host_finished(ip);
+        }
+        assert!(clock.hosts.is_empty());
+    }
+}
@@ -0,0 +1,276 @@
+//! Configuration of a `Scanner`, set one option at a time.
+use super::cancel::Cancellation;
+use super::rtt::RttEstimator;
+use super::{
+    BannerGrab, Checkpointing, NetTransport, ProxyChain, RateLimiter, RttTimeouts, Scanner,
+    SourceBinding, SynScanner, TimeBudget, Transport,
+};
+use crate::address::TargetSet;
+use crate::port_strategy::PortStrategy;
+use std::num::NonZeroU8;
+use std::time::Duration;
+
+/// Builds a `Scanner`, every option left out keeping its default.
+///
+/// ```rust
+/// # use std::{net::IpAddr, time::Duration};
+/// # use rustscan::input::ScanOrder;
+/// # use rustscan::port_strategy::PortStrategy;
+/// # use rustscan::scanner::Scanner;
+/// let addrs = vec!["127.0.0.1".parse::<IpAddr>().unwrap()];
+/// let strategy = PortStrategy::pick(&None, Some(vec![80, 443]), ScanOrder::Serial);
+/// let scanner = Scanner::builder(&addrs, strategy)
+///     .batch_size(100)
+///     .timeout(Duration::from_millis(500))
+///     .build()
+///     .unwrap();
+/// ```
+#[derive(Debug)]
+pub struct ScannerBuilder {
+    ips: TargetSet,
+    port_strategy: PortStrategy,
+    batch_size: u16,
+    timeout: Duration,
+    tries: u8,
+    exclude_ports: Vec<u16>,
+    udp: bool,
+    min_batch_size: Option<u16>,
+    rate_limiter: RateLimiter,
+    banner: Option<BannerGrab>,
+    syn: Option<SynScanner>,
+    rtt_timeouts: Option<RttTimeouts>,
+    source: SourceBinding,
+    proxy: Option<ProxyChain>,
+    checkpoint: Option<Checkpointing>,
+    budget: TimeBudget,
+    udp_ports: Vec<u16>,
+    transport: Option<Box<dyn Transport>>,
+}
+
+impl ScannerBuilder {
+    pub(super) fn new(ips: TargetSet, port_strategy: PortStrategy) -> Self {
+        Self {
+            ips,
+            port_strategy,
+            batch_size: 4500,
+            timeout: Duration::from_millis(1500),
+            tries: 1,
+            exclude_ports: vec![],
+            udp: false,
+            min_batch_size: None,
+            rate_limiter: RateLimiter::unlimited(),
+            banner: None,
+            syn: None,
+            rtt_timeouts: None,
+            source: SourceBinding::default(),
+            proxy: None,
+            checkpoint: None,
+            budget: TimeBudget::default(),
+            udp_ports: vec![],
+            transport: None,
+        }
+    }
+
+    /// How many sockets are scanned at a time, 4500 by default.
+    pub fn batch_size(mut self, batch_size: u16) -> Self {
+        self.batch_size = batch_size;
+        self
+    }
+
+    /// How long to wait before declaring a port closed, 1.5 seconds by
+    /// default.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// How many times a port is probed before declaring it closed, 0 being
+    /// corrected to 1.
+    pub fn tries(mut self, tries: u8) -> Self {
+        self.tries = tries;
+        self
+    }
+
+    /// Ports of the port strategy and of `udp_ports` that are left out.
+    pub fn exclude_ports(mut self, exclude_ports: Vec<u16>) -> Self {
+        self.exclude_ports = exclude_ports;
+        self
+    }
+
+    /// Scans the ports of the port strategy over UDP instead of TCP.
+    pub fn udp(mut self, udp: bool) -> Self {
+        self.udp = udp;
+        self
+    }
+
+    /// Lets the batch size adapt between this and `batch_size`, depending
+    /// on how the network copes.
+    pub fn min_batch_size(mut self, min_batch_size: impl Into<Option<u16>>) -> Self {
+        self.min_batch_size = min_batch_size.into();
+        self
+    }
+
+    /// Caps how many probes per second are sent, globally and per host.
+    pub fn rate_limiter(mut self, rate_limiter: RateLimiter) -> Self {
+        self.rate_limiter = rate_limiter;
+        self
+    }
+
+    /// Reads what services send on open TCP ports before closing the
+    /// connection.
+    pub fn banner(mut self, banner: impl Into<Option<BannerGrab>>) -> Self {
+        self.banner = banner.into();
+        self
+    }
+
+    /// Scans IPv4 TCP ports with half-open SYN probes instead of connecting
+    /// to them.
+    pub fn syn(mut self, syn: impl Into<Option<SynScanner>>) -> Self {
+        self.syn = syn.into();
+        self
+    }
+
+    /// Derives the timeout of every host from its round trip times, within
+    /// the given bounds.
+    pub fn rtt_timeouts(mut self, rtt_timeouts: impl Into<Option<RttTimeouts>>) -> Self {
+        self.rtt_timeouts = rtt_timeouts.into();
+        self
+    }
+
+    /// The address, interface and port probes leave from, all chosen by
+    /// the OS by default. Only the network transport binds to it.
+    pub fn source(mut self, source: SourceBinding) -> Self {
+        self.source = source;
+        self
+    }
+
+    /// The chain of proxies every TCP connection goes through.
+    pub fn proxy(mut self, proxy: impl Into<Option<ProxyChain>>) -> Self {
+        self.proxy = proxy.into();
+        self
+    }
+
+    /// Saves the progress of the scan regularly and starts it where its
+    /// state left off.
+    pub fn checkpoint(mut self, checkpoint: impl Into<Option<Checkpointing>>) -> Self {
+        self.checkpoint = checkpoint.into();
+        self
+    }
+
+    /// Caps how long the scan may take as a whole and for each host, the
+    /// ports left over are reported incomplete.
+    pub fn budget(mut self, budget: TimeBudget) -> Self {
+        self.budget = budget;
+        self
+    }
+
+    /// UDP ports scanned alongside the ports of the port strategy.
+    pub fn udp_ports(mut self, udp_ports: Vec<u16>) -> Self {
+        self.udp_ports = udp_ports;
+        self
+    }
+
+    /// What connections and UDP probes go through, the network from
+    /// `source` by default.
+    pub fn transport(mut self, transport: impl Transport + 'static) -> Self {
+        self.transport = Some(Box::new(transport));
+        self
+    }
+
+    /// Builds the scanner, or tells which options can't go together.
+    pub fn build(self) -> Result<Scanner, String> {
+        if self.batch_size == 0 {
+            return Err("The batch size must be at least 1.".to_owned());
+        }
+        if self.timeout.is_zero() {
+            return Err("The timeout must be longer than zero.".to_owned());
+        }
+        if self.proxy.is_some() && (self.udp || !self.udp_ports.is_empty()) {
+            return Err("UDP ports can't be scanned through a proxy.".to_owned());
+        }
+        if self.syn.is_some() {
+            if self.udp {
+                return Err("A SYN scan only scans TCP ports.".to_owned());
+            }
+            if self.banner.is_some() {
+                return Err("A SYN scan never connects, it can't grab banners.".to_owned());
+            }
+            if self.proxy.is_some() {
+                return Err("A SYN scan can't go through a proxy.".to_owned());
+            }
+        }
+
+        let timeout = self.timeout;
+        let transport = match self.transport {
+            Some(transport) => transport,
+            None => Box::new(NetTransport::new(self.source)),
+        };
+        Ok(Scanner {
+            ips: self.ips,
+            batch_size: self.batch_size,
+            timeout,
+            tries: NonZeroU8::new(self.tries.max(1)).unwrap(),
+            port_strategy: self.port_strategy,
+            exclude_ports: self.exclude_ports,
+            udp: self.udp,
+            min_batch_size: self.min_batch_size,
+            rate_limiter: self.rate_limiter,
+            banner: self.banner,
+            syn: self.syn,
+            rtt: self
+                .rtt_timeouts
+                .map(|bounds| RttEstimator::new(timeout, bounds)),
+            proxy: self.proxy,
+            checkpoint: self.checkpoint,
+            budget: self.budget,
+            udp_ports: self.udp_ports,
+            cancellation: Cancellation::new(),
+            transport,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{Proxy, ProxyChain, Scanner};
+    use crate::input::ScanOrder;
+    use crate::port_strategy::PortStrategy;
+    use std::net::IpAddr;
+    use std::time::Duration;
+
+    fn builder() -> super::ScannerBuilder {
+        let addrs = vec!["127.0.0.1".parse::<IpAddr>().unwrap()];
+        let strategy = PortStrategy::pick(&None, Some(vec![80]), ScanOrder::Serial);
+        Scanner::builder(&addrs, strategy)
+    }
+
+    #[test]
+    fn builds_with_defaults() {
+        let scanner = builder().build().unwrap();
+        assert_eq!(scanner.batch_size, 4500);
+        assert_eq!(scanner.timeout, Duration::from_millis(1500));
+        assert_eq!(scanner.tries.get(), 1);
+        assert!(!scanner.udp);
+    }
+
+    #[test]
+    fn corrects_zero_tries() {
+        assert_eq!(builder().tries(0).build().unwrap().tries.get(), 1);
+    }
+
+    #[test]
+    fn rejects_invalid_options() {
+        assert!(builder().batch_size(0).build().is_err());
+        assert!(builder().timeout(Duration::ZERO).build().is_err());
+
+        let proxy =
+            || ProxyChain::new(vec!["socks5://127.0.0.1:1080".parse::<Proxy>().unwrap()]).unwrap();
+        assert!(builder().proxy(proxy()).build().is_ok());
+        assert!(builder().proxy(proxy()).udp(true).build().is_err());
+        assert!(builder()
+            .proxy(proxy())
+            .udp_ports(vec![53])
+            .build()
+            .is_err());
+    }
+}
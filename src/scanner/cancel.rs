@@ -0,0 +1,86 @@
+//! Stops a running scan from the outside, such as from a signal handler,
+//! keeping whatever it found so far.
+use async_std::channel::{self, Receiver, Sender};
+
+/// Cancels the scan of the `Scanner` it was taken from. Clones cancel the
+/// same scan, and the handle can be moved to any thread.
+///
+/// ```rust
+/// # use async_std::task::block_on;
+/// # use std::{net::IpAddr, time::Duration};
+/// # use rustscan::input::ScanOrder;
+/// # use rustscan::port_strategy::PortStrategy;
+/// # use rustscan::scanner::{Protocol, Scanner};
+/// # let addrs = vec!["127.0.0.1".parse::<IpAddr>().unwrap()];
+/// # let strategy = PortStrategy::pick(&None, Some(vec![80, 443]), ScanOrder::Serial);
+/// # let scanner = Scanner::builder(&addrs, strategy).batch_size(10).timeout(Duration::from_millis(100)).build().unwrap();
+/// let handle = scanner.cancel_handle();
+/// handle.cancel();
+/// let report = block_on(scanner.run());
+/// assert!(report.open_sockets(Protocol::Tcp).is_empty());
+/// ```
+#[derive(Debug, Clone)]
+pub struct CancelHandle {
+    sender: Sender<()>,
+}
+
+impl CancelHandle {
+    /// Stops scheduling new sockets. The ones already in flight get one more
+    /// timeout to finish, then the scan returns what it found.
+    pub fn cancel(&self) {
+        self.sender.close();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.sender.is_closed()
+    }
+}
+
+/// The scanner side of its cancel handles. Nothing is ever sent on the
+/// channel, closing it wakes up everyone waiting on it.
+#[derive(Debug)]
+pub(crate) struct Cancellation {
+    sender: Sender<()>,
+    receiver: Receiver<()>,
+}
+
+impl Cancellation {
+    pub(crate) fn new() -> Self {
+        let (sender, receiver) = channel::bounded(1);
+        Self { sender, receiver }
+    }
+
+    pub(crate) fn handle(&self) -> CancelHandle {
+        CancelHandle {
+            sender: self.sender.clone(),
+        }
+    }
+
+    pub(crate) fn is_cancelled(&self) -> bool {
+        self.sender.is_closed()
+    }
+
+    /// Resolves once the scan is cancelled.
+    pub(crate) async fn cancelled(&self) {
+        let _ = self.receiver.recv().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Cancellation;
+    use async_std::task::block_on;
+
+    #[test]
+    fn handles_cancel_the_same_scan() {
+        let cancellation = Cancellation::new();
+        let handle = cancellation.handle();
+        let other = handle.clone();
+        assert!(!cancellation.is_cancelled());
+
+        other.cancel();
+        assert!(cancellation.is_cancelled());
+        assert!(handle.is_cancelled());
+        block_on(cancellation.cancelled());
+    }
+}
@@ -0,0 +1,132 @@
+//! Saves the progress of a scan to disk, so that an interrupted scan can be
+//! continued where it stopped instead of starting over.
+use crate::address::TargetSet;
+use log::warn;
+use serde_derive::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Everything needed to continue a scan: what was scanned, in which order,
+/// how far it went and what it found.
+///
+/// Sockets are scanned in a fixed order given the hosts and the seed of the
+/// port order, `position` counts the sockets of that order known to be done.
+/// Some sockets after it may be done as well, they get scanned again.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Checkpoint {
+    /// The command line the scan was started with.
+    pub args: Vec<String>,
+    /// Seed of the random port order.
+    pub seed: u32,
+    /// The hosts being scanned, as they were after host discovery.
+    pub ips: TargetSet,
+    pub position: usize,
+    /// The open sockets found so far, of the protocol of the port list.
+    pub open: Vec<SocketAddr>,
+    /// The open sockets found so far among the UDP ports scanned alongside
+    /// TCP ones.
+    #[serde(default)]
+    pub open_udp: Vec<SocketAddr>,
+}
+
+impl Checkpoint {
+    /// Reads a checkpoint saved by `write`.
+    pub fn read(path: &Path) -> io::Result<Self> {
+        let content = fs::read_to_string(path)?;
+        toml::from_str(&content).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Saves the checkpoint to `path`. The file is replaced at once, a
+    /// crash while writing leaves the previous checkpoint untouched.
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        let content = toml::to_string(self).map_err(io::Error::other)?;
+        let mut partial = path.as_os_str().to_owned();
+        partial.push(".partial");
+        fs::write(&partial, content)?;
+        fs::rename(&partial, path)
+    }
+}
+
+/// Where and how often a scan saves its progress, starting from `state`: an
+/// empty checkpoint for a new scan, or the one read back when resuming.
+#[derive(Debug, Clone)]
+pub struct Checkpointing {
+    pub path: PathBuf,
+    pub interval: Duration,
+    pub state: Checkpoint,
+}
+
+impl Checkpointing {
+    /// Saves `state` to the file, only warning when it fails since the scan
+    /// itself can go on.
+    pub(crate) fn save(&self, state: &Checkpoint) {
+        if let Err(e) = state.write(&self.path) {
+            warn!(
+                "Could not save the scan progress to {}: {}",
+                self.path.display(),
+                e
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Checkpoint;
+    use crate::address::TargetSet;
+    use cidr_utils::cidr::IpCidr;
+    use std::net::{IpAddr, SocketAddr};
+    use std::str::FromStr;
+
+    #[test]
+    fn checkpoint_round_trip() {
+        let mut ips = TargetSet::from(&["::1".parse::<IpAddr>().unwrap()]);
+        ips.insert_cidr(&IpCidr::from_str("10.0.0.0/8").unwrap());
+        let checkpoint = Checkpoint {
+            args: vec!["rustscan".to_owned(), "-a".to_owned(), "::1".to_owned()],
+            seed: u32::MAX,
+            ips,
+            position: 70_000,
+            open: vec!["[::1]:22".parse::<SocketAddr>().unwrap()],
+            open_udp: vec!["[::1]:53".parse::<SocketAddr>().unwrap()],
+        };
+        let path = std::env::temp_dir().join(format!("rustscan-checkpoint-{}", std::process::id()));
+
+        checkpoint.write(&path).unwrap();
+        let read = Checkpoint::read(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(read.unwrap(), checkpoint);
+    }
+
+    #[test]
+    fn reads_hosts_saved_one_by_one() {
+        let path = std::env::temp_dir().join(format!("rustscan-hosts-{}", std::process::id()));
+        std::fs::write(
+            &path,
+            "args = []\nseed = 1\nips = [\"10.0.0.1\", \"10.0.0.2\", \"::1\"]\nposition = 0\nopen = []\n",
+        )
+        .unwrap();
+        let read = Checkpoint::read(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        let ips: Vec<IpAddr> = read.unwrap().ips.iter().collect();
+        assert_eq!(
+            ips,
+            ["10.0.0.1", "10.0.0.2", "::1"].map(|ip| ip.parse::<IpAddr>().unwrap())
+        );
+    }
+
+    #[test]
+    fn rejects_garbage() {
+        let path = std::env::temp_dir().join(format!("rustscan-garbage-{}", std::process::id()));
+        std::fs::write(&path, "not a checkpoint").unwrap();
+        let read = Checkpoint::read(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(read.unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,281 @@
+//! Grows and shrinks the number of sockets in flight from the timeouts and
+//! refusals each batch comes back with.
+use super::PortState;
+use log::debug;
+
+/// How far the timeout rate of a round may rise above the usual rate before
+/// it is treated as congestion rather than filtered ports.
+const TIMEOUT_RATE_TOLERANCE: f64 = 0.05;
+
+/// How far the share of refused answers may rise above its usual share
+/// before it is treated as a middlebox answering for the targets.
+const REFUSED_RATIO_TOLERANCE: f64 = 0.1;
+
+/// Weight given to the latest round when updating the usual rates.
+const BASELINE_WEIGHT: f64 = 0.25;
+
+/// Number of rounds it should take to grow from the minimum to the maximum
+/// window when the network keeps up.
+const ROUNDS_TO_MAX: usize = 20;
+
+/// An AIMD (additive increase, multiplicative decrease) controller for the
+/// number of sockets scanned at the same time.
+///
+/// Results are counted in rounds of one window worth of sockets. A round
+/// whose timeout rate climbs above the rate usually seen on this scan means
+/// probes are being dropped, so the window is halved. So does a round whose
+/// answers are refused more often than usual, ports that were open turning
+/// into RSTs points to a firewall or IPS rate limiting the scan by
+/// resetting connections on behalf of the targets. Any other round means
+/// the network keeps up and the window grows by a fixed step.
+///
+/// Comparing against the usual rate instead of an absolute threshold keeps
+/// firewalled hosts, which time out on every port no matter the window,
+/// from dragging the window down to the minimum.
+#[derive(Debug)]
+pub struct CongestionController {
+    window: usize,
+    min: usize,
+    max: usize,
+    step: usize,
+    open: usize,
+    refused: usize,
+    timed_out: usize,
+    timeout_baseline: Option<f64>,
+    refused_baseline: Option<f64>,
+}
+
+impl CongestionController {
+    /// A controller starting at `min` which never goes above `max`.
+    pub fn adaptive(min: u16, max: u16) -> Self {
+        let max = usize::from(max).max(1);
+        let min = usize::from(min).clamp(1, max);
+        Self {
+            window: min,
+            min,
+            max,
+            step: ((max - min) / ROUNDS_TO_MAX).max(1),
+            open: 0,
+            refused: 0,
+            timed_out: 0,
+            timeout_baseline: None,
+            refused_baseline: None,
+        }
+    }
+
+    /// A controller whose window never moves from `size`.
+    pub fn fixed(size: u16) -> Self {
+        Self::adaptive(size, size)
+    }
+
+    /// The number of sockets that may be scanned at the same time.
+    pub fn window(&self) -> usize {
+        self.window
+    }
+
+    /// Caps the window below `in_flight`, the number of sockets that were
+    /// holding a file when the process ran out of them. The cap leaves some
+    /// room for the files the rest of the process needs, and stays for the
+    /// rest of the scan.
+    pub fn out_of_files(&mut self, in_flight: usize) {
+        self.max = self.max.min((in_flight * 3 / 4).max(1));
+        self.min = self.min.min(self.max);
+        self.window = self.window.min(self.max);
+        self.step = self.step.min(self.max);
+        debug!("Ran out of files, window capped to {}", self.max);
+    }
+
+    /// The most sockets the window may grow to.
+    pub fn max(&self) -> usize {
+        self.max
+    }
+
+    /// Feeds the state of a finished socket to the controller.
+    pub fn record(&mut self, state: PortState) {
+        match state {
+            PortState::Open => self.open += 1,
+            PortState::Closed => self.refused += 1,
+            PortState::Filtered | PortState::OpenFiltered => self.timed_out += 1,
+            // Other errors say nothing about how busy the network is.
+            PortState::Error(_) => return,
+        }
+
+        if self.open + self.refused + self.timed_out >= self.window {
+            self.end_round();
+        }
+    }
+
+    #[allow(clippy::cast_precision_loss)]
+    fn end_round(&mut self) {
+        let answered = self.open + self.refused;
+        let timeout_rate = self.timed_out as f64 / (answered + self.timed_out) as f64;
+        // A round where nothing answered tells nothing about refusals.
+        let refused_ratio = (answered > 0).then(|| self.refused as f64 / answered as f64);
+
+        let timeouts_rose = self
+            .timeout_baseline
+            .is_some_and(|baseline| timeout_rate > baseline + TIMEOUT_RATE_TOLERANCE);
+        let refusals_rose = refused_ratio
+            .zip(self.refused_baseline)
+            .is_some_and(|(ratio, baseline)| ratio > baseline + REFUSED_RATIO_TOLERANCE);
+
+        if timeouts_rose || refusals_rose {
+            self.window = (self.window / 2).max(self.min);
+        } else {
+            self.window = (self.window + self.step).min(self.max);
+        }
+
+        self.timeout_baseline = Some(update_baseline(self.timeout_baseline, timeout_rate));
+        if let Some(ratio) = refused_ratio {
+            self.refused_baseline = Some(update_baseline(self.refused_baseline, ratio));
+        }
+        debug!(
+            "Round ended with a timeout rate of {:.2} and a refused ratio of {:.2?}, window is now {}",
+            timeout_rate, refused_ratio, self.window
+        );
+
+        self.open = 0;
+        self.refused = 0;
+        self.timed_out = 0;
+    }
+}
+
+/// Moves the usual rate towards the rate of the latest round.
+fn update_baseline(baseline: Option<f64>, rate: f64) -> f64 {
+    match baseline {
+        Some(baseline) => baseline + BASELINE_WEIGHT * (rate - baseline),
+        None => rate,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CongestionController;
+    use crate::scanner::PortState;
+
+    fn round(controller: &mut CongestionController, timed_out: usize) {
+        let window = controller.window();
+        for i in 0..window {
+            if i < timed_out {
+                controller.record(PortState::Filtered);
+            } else {
+                controller.record(PortState::Closed);
+            }
+        }
+    }
+
+    #[test]
+    fn fixed_window_never_moves() {
+        let mut controller = CongestionController::fixed(100);
+        round(&mut controller, 0);
+        round(&mut controller, 100);
+        assert_eq!(controller.window(), 100);
+    }
+
+    #[test]
+    fn grows_additively_while_answers_come_back() {
+        let mut controller = CongestionController::adaptive(100, 2_100);
+        round(&mut controller, 0);
+        assert_eq!(controller.window(), 200);
+        round(&mut controller, 0);
+        assert_eq!(controller.window(), 300);
+
+        for _ in 0..100 {
+            round(&mut controller, 0);
+        }
+        assert_eq!(controller.window(), 2_100);
+    }
+
+    #[test]
+    fn shrinks_multiplicatively_when_timeouts_rise() {
+        let mut controller = CongestionController::adaptive(100, 2_100);
+        for _ in 0..10 {
+            round(&mut controller, 0);
+        }
+        assert_eq!(controller.window(), 1_100);
+
+        round(&mut controller, 500);
+        assert_eq!(controller.window(), 550);
+    }
+
+    #[test]
+    fn steady_timeouts_do_not_shrink_the_window() {
+        // A firewalled host times out on every port whatever the window is.
+        let mut controller = CongestionController::adaptive(100, 2_100);
+        for _ in 0..5 {
+            let window = controller.window();
+            round(&mut controller, window);
+        }
+        assert_eq!(controller.window(), 600);
+    }
+
+    #[test]
+    fn shrinks_when_open_ports_turn_into_refusals() {
+        let mut controller = CongestionController::adaptive(100, 2_100);
+        // Half of the answers are open, until a middlebox resets them all.
+        let answers = |controller: &mut CongestionController, open: usize| {
+            for i in 0..controller.window() {
+                if i < open {
+                    controller.record(PortState::Open);
+                } else {
+                    controller.record(PortState::Closed);
+                }
+            }
+        };
+        for _ in 0..10 {
+            let window = controller.window();
+            answers(&mut controller, window / 2);
+        }
+        assert_eq!(controller.window(), 1_100);
+
+        answers(&mut controller, 0);
+        assert_eq!(controller.window(), 550);
+    }
+
+    #[test]
+    fn steady_refusals_do_not_shrink_the_window() {
+        // Most ports of most hosts are closed.
+        let mut controller = CongestionController::adaptive(100, 2_100);
+        for _ in 0..5 {
+            round(&mut controller, 0);
+        }
+        assert_eq!(controller.window(), 600);
+    }
+
+    #[test]
+    fn running_out_of_files_caps_the_window() {
+        let mut controller = CongestionController::fixed(5_000);
+        // A burst of failures, each one seeing fewer sockets in flight.
+        for in_flight in (1_024..4_999).rev() {
+            controller.out_of_files(in_flight);
+        }
+        assert_eq!(controller.window(), 768);
+        assert_eq!(controller.max(), 768);
+
+        round(&mut controller, 0);
+        assert_eq!(controller.window(), 768);
+    }
+
+    #[test]
+    fn adaptive_window_grows_back_up_to_the_cap() {
+        let mut controller = CongestionController::adaptive(100, 2_100);
+        controller.out_of_files(400);
+        assert_eq!(controller.window(), 100);
+
+        for _ in 0..10 {
+            round(&mut controller, 0);
+        }
+        assert_eq!(controller.window(), 300);
+    }
+
+    #[test]
+    fn never_goes_below_the_minimum() {
+        let mut controller = CongestionController::adaptive(100, 2_100);
+        round(&mut controller, 0);
+        for _ in 0..10 {
+            let window = controller.window();
+            round(&mut controller, window);
+        }
+        assert!(controller.window() >= 100);
+    }
+}
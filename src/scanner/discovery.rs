@@ -0,0 +1,532 @@
+//! Finds out which hosts are up before their ports get scanned, so that a
+//! dead host doesn't cost a timeout for every single port.
+//!
+//! A host is up as soon as it answers a TCP connect ping, with an accepted
+//! or a refused connection, or an ICMP echo request. Echo requests go
+//! through unprivileged ping sockets, only available on Linux when the
+//! group of the process is within `net.ipv4.ping_group_range`.
+use super::{RateLimiter, SourceBinding};
+use async_std::io;
+use async_std::net::TcpStream;
+use futures::channel::mpsc;
+use futures::future;
+use futures::stream::{self, FuturesUnordered, Stream};
+use futures::StreamExt;
+use log::debug;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+#[cfg(target_os = "linux")]
+use {
+    futures::channel::oneshot,
+    socket2::{Domain, Protocol, Socket, Type},
+    std::collections::HashMap,
+    std::net::{Ipv4Addr, Ipv6Addr, UdpSocket},
+    std::sync::atomic::{AtomicBool, AtomicU16, Ordering},
+    std::sync::{Arc, Mutex},
+    std::thread,
+};
+
+/// Why a host is believed to be up, or that it isn't.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HostStatus {
+    /// A TCP connect ping to this port was accepted or refused.
+    TcpPing(u16),
+    /// The host answered an ICMP echo request.
+    EchoReply,
+    /// Nothing answered before the timeout.
+    Down,
+}
+
+impl HostStatus {
+    pub fn is_up(self) -> bool {
+        self != HostStatus::Down
+    }
+}
+
+impl fmt::Display for HostStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HostStatus::TcpPing(port) => write!(f, "up, port {port} answered"),
+            HostStatus::EchoReply => write!(f, "up, echo reply"),
+            HostStatus::Down => write!(f, "down"),
+        }
+    }
+}
+
+/// The hosts discovery found up. The down ones are only counted, a range
+/// may hold too many of them to keep.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct DiscoveryReport {
+    up: BTreeMap<IpAddr, HostStatus>,
+    down: u128,
+}
+
+impl DiscoveryReport {
+    /// Records the status of a host that went through discovery.
+    pub fn record(&mut self, ip: IpAddr, status: HostStatus) {
+        if status.is_up() {
+            self.up.insert(ip, status);
+        } else {
+            self.down += 1;
+        }
+    }
+
+    /// Why `ip` is up, None when it is down or was never pinged.
+    pub fn status(&self, ip: IpAddr) -> Option<HostStatus> {
+        self.up.get(&ip).copied()
+    }
+
+    /// The hosts found up, the ones worth port scanning.
+    pub fn up(&self) -> Vec<IpAddr> {
+        self.up.keys().copied().collect()
+    }
+
+    /// Iterates over the hosts found up and how each one answered, ordered
+    /// by IP.
+    pub fn statuses(&self) -> impl Iterator<Item = (&IpAddr, &HostStatus)> {
+        self.up.iter()
+    }
+
+    /// How many hosts nothing answered for.
+    pub fn down(&self) -> u128 {
+        self.down
+    }
+}
+
+/// Pings hosts with TCP connects to a few ports, and ICMP echo requests
+/// when ping sockets are allowed.
+///
+/// ```rust
+/// # use async_std::task::block_on;
+/// # use rustscan::scanner::{HostDiscovery, RateLimiter, SourceBinding};
+/// # use std::time::Duration;
+/// let discovery = HostDiscovery::new(
+///     vec![80, 443],
+///     Duration::from_millis(500),
+///     100,
+///     SourceBinding::default(),
+///     RateLimiter::unlimited(),
+/// );
+/// let report = block_on(discovery.run(["127.0.0.1".parse().unwrap()]));
+/// println!("{:?}", report.up());
+/// ```
+#[derive(Debug)]
+pub struct HostDiscovery {
+    ping_ports: Vec<u16>,
+    timeout: Duration,
+    batch_size: u16,
+    source: SourceBinding,
+    rate_limiter: RateLimiter,
+    #[cfg(target_os = "linux")]
+    echo: EchoPinger,
+}
+
+impl HostDiscovery {
+    /// Builds a discovery pinging `ping_ports` over TCP, waiting `timeout`
+    /// for each host, with at most `batch_size` hosts pinged at a time and
+    /// every ping leaving from `source`. Each ping, TCP or ICMP, waits for
+    /// `rate_limiter` first.
+    pub fn new(
+        ping_ports: Vec<u16>,
+        timeout: Duration,
+        batch_size: u16,
+        source: SourceBinding,
+        rate_limiter: RateLimiter,
+    ) -> Self {
+        Self {
+            ping_ports,
+            timeout,
+            batch_size,
+            rate_limiter,
+            #[cfg(target_os = "linux")]
+            echo: EchoPinger::new(&source),
+            source,
+        }
+    }
+
+    /// Pings every host of `ips`, reporting which ones are up.
+    pub async fn run(&self, ips: impl IntoIterator<Item = IpAddr>) -> DiscoveryReport {
+        let mut report = DiscoveryReport::default();
+        self.discover(ips, |ip, status| report.record(ip, status))
+            .await;
+        report
+    }
+
+    /// Pings the same hosts as `run`, but yields every host with its status
+    /// as soon as it is known instead of keeping them.
+    ///
+    /// The returned stream is not `Unpin`, pin it before polling.
+    pub fn stream<'a, I>(&'a self, ips: I) -> impl Stream<Item = (IpAddr, HostStatus)> + 'a
+    where
+        I: IntoIterator<Item = IpAddr> + 'a,
+    {
+        let (sender, receiver) = mpsc::unbounded();
+        let discover = self.discover(ips, move |ip, status| {
+            // The receiver only goes away along with this future.
+            let _ = sender.unbounded_send((ip, status));
+        });
+        let driver = stream::once(discover).filter_map(|()| future::ready(None));
+        stream::select(receiver, driver)
+    }
+
+    /// Pings the hosts of `ips`, handing each one to `emit` with its status.
+    async fn discover<F: FnMut(IpAddr, HostStatus)>(
+        &self,
+        ips: impl IntoIterator<Item = IpAddr>,
+        mut emit: F,
+    ) {
+        let mut ips = ips.into_iter();
+        // Each host takes one socket per ping port.
+        let hosts_at_once = usize::from(self.batch_size) / self.ping_ports.len().max(1);
+
+        let mut ftrs = FuturesUnordered::new();
+        for ip in ips.by_ref().take(hosts_at_once.max(1)) {
+            ftrs.push(self.ping(ip));
+        }
+
+        while let Some((ip, status)) = ftrs.next().await {
+            if let Some(ip) = ips.next() {
+                ftrs.push(self.ping(ip));
+            }
+            debug!("Host {} is {}", ip, status);
+            emit(ip, status);
+        }
+    }
+
+    /// Pings a host every way at once, returning the first answer.
+    async fn ping(&self, ip: IpAddr) -> (IpAddr, HostStatus) {
+        let mut pings: FuturesUnordered<_> = self
+            .ping_ports
+            .iter()
+            .map(|port| self.ping_with(ip, Ping::Tcp(*port)))
+            .collect();
+        #[cfg(target_os = "linux")]
+        pings.push(self.ping_with(ip, Ping::Echo));
+
+        while let Some(result) = pings.next().await {
+            if let Some(status) = result {
+                return (ip, status);
+            }
+        }
+        (ip, HostStatus::Down)
+    }
+
+    async fn ping_with(&self, ip: IpAddr, ping: Ping) -> Option<HostStatus> {
+        self.rate_limiter.acquire(ip).await;
+        match ping {
+            Ping::Tcp(port) => {
+                // A refused connection comes from a live host too.
+                let socket = SocketAddr::new(ip, port);
+                let connect = async {
+                    if self.source.is_default() {
+                        TcpStream::connect(socket).await
+                    } else {
+                        self.source.connect(socket).await
+                    }
+                };
+                let answered = match io::timeout(self.timeout, connect).await {
+                    Ok(_) => true,
+                    Err(e) => e.kind() == io::ErrorKind::ConnectionRefused,
+                };
+                answered.then_some(HostStatus::TcpPing(port))
+            }
+            #[cfg(target_os = "linux")]
+            Ping::Echo => self
+                .echo
+                .ping(ip, self.timeout)
+                .await
+                .then_some(HostStatus::EchoReply),
+        }
+    }
+}
+
+enum Ping {
+    Tcp(u16),
+    #[cfg(target_os = "linux")]
+    Echo,
+}
+
+/// Sends ICMP echo requests over unprivileged ping sockets, one per IP
+/// version, matching the replies by source address on a receiving thread.
+#[cfg(target_os = "linux")]
+#[derive(Debug)]
+struct EchoPinger {
+    v4: Option<Arc<PingSocket>>,
+    v6: Option<Arc<PingSocket>>,
+}
+
+#[cfg(target_os = "linux")]
+#[derive(Debug)]
+struct PingSocket {
+    socket: UdpSocket,
+    echo_request: u8,
+    echo_reply: u8,
+    sequence: AtomicU16,
+    /// Echo requests waiting for their reply, by target and sequence number
+    /// so that pings to the same host don't take each other's replies.
+    pending: Mutex<HashMap<(IpAddr, u16), oneshot::Sender<()>>>,
+    closed: AtomicBool,
+}
+
+/// How often the receiving threads check whether the pinger was dropped.
+#[cfg(target_os = "linux")]
+const RECEIVE_POLL: Duration = Duration::from_millis(100);
+
+#[cfg(target_os = "linux")]
+impl EchoPinger {
+    fn new(source: &SourceBinding) -> Self {
+        Self {
+            v4: PingSocket::open(source, IpAddr::V4(Ipv4Addr::UNSPECIFIED), 8, 0),
+            v6: PingSocket::open(source, IpAddr::V6(Ipv6Addr::UNSPECIFIED), 128, 129),
+        }
+    }
+
+    /// Whether `ip` answered an echo request within `wait`. Always false
+    /// when ping sockets are not allowed.
+    async fn ping(&self, ip: IpAddr, wait: Duration) -> bool {
+        let socket = match ip {
+            IpAddr::V4(_) => &self.v4,
+            IpAddr::V6(_) => &self.v6,
+        };
+        let Some(socket) = socket else {
+            return false;
+        };
+
+        let sequence = socket.sequence.fetch_add(1, Ordering::Relaxed);
+        let (sender, receiver) = oneshot::channel();
+        socket
+            .pending
+            .lock()
+            .unwrap()
+            .insert((ip, sequence), sender);
+        let answered = socket.send(ip, sequence).is_ok()
+            && io::timeout(wait, async { Ok(receiver.await.is_ok()) })
+                .await
+                .unwrap_or(false);
+        socket.pending.lock().unwrap().remove(&(ip, sequence));
+        answered
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for EchoPinger {
+    fn drop(&mut self) {
+        for socket in self.v4.iter().chain(&self.v6) {
+            socket.closed.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl PingSocket {
+    /// Opens a ping socket for the IP version of `family`, bound to
+    /// `source`, and starts its receiving thread. None when the system does
+    /// not allow it or the source can't be used with this IP version.
+    fn open(
+        source: &SourceBinding,
+        family: IpAddr,
+        echo_request: u8,
+        echo_reply: u8,
+    ) -> Option<Arc<Self>> {
+        let (domain, protocol) = match family {
+            IpAddr::V4(_) => (Domain::IPV4, Protocol::ICMPV4),
+            IpAddr::V6(_) => (Domain::IPV6, Protocol::ICMPV6),
+        };
+        let socket = Socket::new(domain, Type::DGRAM, Some(protocol))
+            .and_then(|socket| {
+                socket.set_read_timeout(Some(RECEIVE_POLL))?;
+                source.bind(&socket, family, None)?;
+                Ok(socket)
+            })
+            .map_err(|e| debug!("ICMP echo pings are not available {}", e))
+            .ok()?;
+
+        let socket = Arc::new(Self {
+            // A ping socket is a datagram socket, the kernel takes care of
+            // the identifier and checksum of every message.
+            socket: UdpSocket::from(socket),
+            echo_request,
+            echo_reply,
+            sequence: AtomicU16::new(0),
+            pending: Mutex::default(),
+            closed: AtomicBool::new(false),
+        });
+
+        let receiver = Arc::clone(&socket);
+        thread::Builder::new()
+            .name("echo-receiver".to_owned())
+            .spawn(move || receiver.receive())
+            .map_err(|e| debug!("Failed to start the echo receiver {}", e))
+            .ok()?;
+
+        Some(socket)
+    }
+
+    fn send(&self, ip: IpAddr, sequence: u16) -> io::Result<()> {
+        let mut message = [0u8; 16];
+        message[0] = self.echo_request;
+        message[6..8].copy_from_slice(&sequence.to_be_bytes());
+        message[8..].copy_from_slice(b"rustscan");
+        self.socket.send_to(&message, SocketAddr::new(ip, 0))?;
+        Ok(())
+    }
+
+    fn receive(&self) {
+        let mut buf = [0u8; 1500];
+        while !self.closed.load(Ordering::Relaxed) {
+            match self.socket.recv_from(&mut buf) {
+                Ok((size, from)) if size >= 8 && buf[0] == self.echo_reply => {
+                    // Replies carry the sequence number of their request.
+                    let sequence = u16::from_be_bytes([buf[6], buf[7]]);
+                    let pending = self.pending.lock().unwrap().remove(&(from.ip(), sequence));
+                    if let Some(sender) = pending {
+                        let _ = sender.send(());
+                    }
+                }
+                Ok(_) => {}
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) => {}
+                Err(e) => {
+                    debug!("Stopped receiving echo replies {}", e);
+                    break;
+                }
+            }
+        }
+        self.pending.lock().unwrap().clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{HostDiscovery, HostStatus, RateLimiter, SourceBinding};
+    use async_std::task::block_on;
+    use futures::StreamExt;
+    use std::net::{IpAddr, TcpListener};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn listening_host_is_up() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+
+        let discovery = HostDiscovery::new(
+            vec![port],
+            Duration::from_millis(500),
+            10,
+            SourceBinding::default(),
+            RateLimiter::unlimited(),
+        );
+        let report = block_on(discovery.run([ip]));
+
+        assert!(report.status(ip).unwrap().is_up());
+        assert_eq!(report.up(), vec![ip]);
+        assert_eq!(
+            report.statuses().collect::<Vec<_>>(),
+            vec![(&ip, &report.status(ip).unwrap())]
+        );
+        assert_eq!(report.down(), 0);
+    }
+
+    #[test]
+    fn refused_connection_means_up() {
+        let port = {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap().port()
+        };
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+
+        let discovery = HostDiscovery::new(
+            vec![port],
+            Duration::from_millis(500),
+            10,
+            SourceBinding::default(),
+            RateLimiter::unlimited(),
+        );
+        let report = block_on(discovery.run([ip]));
+
+        assert!(report.status(ip).unwrap().is_up());
+    }
+
+    #[test]
+    fn silent_host_is_down() {
+        let ip = "192.0.2.1".parse::<IpAddr>().unwrap();
+        // Nothing to ping with, some sandboxes answer for any address.
+        let discovery = HostDiscovery {
+            ping_ports: vec![],
+            timeout: Duration::from_millis(200),
+            batch_size: 10,
+            source: SourceBinding::default(),
+            rate_limiter: RateLimiter::unlimited(),
+            #[cfg(target_os = "linux")]
+            echo: super::EchoPinger { v4: None, v6: None },
+        };
+        let report = block_on(discovery.run([ip]));
+
+        assert_eq!(report.status(ip), None);
+        assert_eq!(report.down(), 1);
+        assert!(report.up().is_empty());
+
+        let hosts: Vec<_> = block_on(discovery.stream([ip]).collect());
+        assert_eq!(hosts, vec![(ip, HostStatus::Down)]);
+    }
+
+    #[test]
+    fn pings_wait_for_the_rate_limiter() {
+        let port = {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap().port()
+        };
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+        // The second ping of the host waits 100ms for its slot.
+        let discovery = HostDiscovery {
+            ping_ports: vec![port],
+            timeout: Duration::from_millis(500),
+            batch_size: 10,
+            source: SourceBinding::default(),
+            rate_limiter: RateLimiter::new(None, Some(10)),
+            #[cfg(target_os = "linux")]
+            echo: super::EchoPinger { v4: None, v6: None },
+        };
+        let started = Instant::now();
+        block_on(discovery.run([ip, ip]));
+
+        assert!(started.elapsed() >= Duration::from_millis(100));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn loopback_answers_echo_requests() {
+        let pinger = super::EchoPinger::new(&SourceBinding::default());
+        // Ping sockets depend on net.ipv4.ping_group_range.
+        if pinger.v4.is_none() {
+            return;
+        }
+
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+        assert!(block_on(pinger.ping(ip, Duration::from_secs(1))));
+
+        // Pings to the same host in flight together each get their reply.
+        let (first, second) = block_on(futures::future::join(
+            pinger.ping(ip, Duration::from_secs(1)),
+            pinger.ping(ip, Duration::from_secs(1)),
+        ));
+        assert!(first && second);
+    }
+
+    #[test]
+    fn status_display() {
+        assert_eq!(
+            HostStatus::TcpPing(443).to_string(),
+            "up, port 443 answered"
+        );
+        assert_eq!(HostStatus::EchoReply.to_string(), "up, echo reply");
+        assert_eq!(HostStatus::Down.to_string(), "down");
+    }
+}
@@ -1,75 +1,182 @@
 //! Core functionality for actual scanning behaviour.
-use crate::generated::get_parsed_data;
+use crate::address::TargetSet;
 use crate::port_strategy::PortStrategy;
 use log::debug;
 
+mod banner;
+pub use banner::{Banner, BannerGrab};
+
+mod builder;
+pub use builder::ScannerBuilder;
+
+mod budget;
+use budget::BudgetClock;
+pub use budget::TimeBudget;
+
+mod cancel;
+pub use cancel::CancelHandle;
+use cancel::Cancellation;
+
+mod checkpoint;
+pub use checkpoint::{Checkpoint, Checkpointing};
+
+mod congestion;
+use congestion::CongestionController;
+
+mod discovery;
+pub use discovery::{DiscoveryReport, HostDiscovery, HostStatus};
+
+mod proxy;
+pub use proxy::{Credentials, Proxy, ProxyChain, ProxyProtocol};
+
+mod rate_limit;
+pub use rate_limit::RateLimiter;
+
+mod report;
+pub use report::{HostSummary, PortState, Protocol, ScanEvent, ScanReport};
+
+mod rtt;
+use rtt::RttEstimator;
+pub use rtt::RttTimeouts;
+
+mod service;
+pub use service::ServiceMatch;
+
+mod source;
+pub use source::SourceBinding;
+
+mod syn;
+pub use syn::SynScanner;
+
+mod tls;
+pub use tls::{CertificateInfo, TlsInfo};
+
+mod udp;
+
+mod simulated;
+pub use simulated::{SimulatedNetwork, SocketBehavior};
+
+mod transport;
+pub use transport::{Connection, NetTransport, Transport};
+
 mod socket_iterator;
 use socket_iterator::SocketIterator;
 
-use async_std::net::TcpStream;
-use async_std::prelude::*;
-use async_std::{io, net::UdpSocket};
-use colored::Colorize;
-use futures::stream::FuturesUnordered;
-use std::collections::BTreeMap;
+use async_std::io;
+use futures::channel::mpsc;
+use futures::future::{self, Either};
+use futures::stream::{self, FuturesUnordered, Stream};
+use futures::{AsyncWriteExt, FutureExt, StreamExt};
+use std::collections::{BTreeSet, VecDeque};
 use std::{
-    collections::HashSet,
-    net::{IpAddr, Shutdown, SocketAddr},
+    collections::{HashMap, HashSet},
+    convert::TryFrom,
+    net::{IpAddr, SocketAddr, SocketAddrV4},
     num::NonZeroU8,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
-/// The class for the scanner
-/// IP is data type IpAddr and is the IP address
-/// start & end is where the port scan starts and ends
-/// batch_size is how many ports at a time should be scanned
-/// Timeout is the time RustScan should wait before declaring a port closed. As datatype Duration.
-/// greppable is whether or not RustScan should print things, or wait until the end to print only the ip and open ports.
+/// The class for the scanner, built with `Scanner::builder`.
+///
+/// It only scans and tells what it found, through a `ScanReport` or a
+/// stream of `ScanEvent`s. Printing the results is left to the caller.
 #[cfg(not(tarpaulin_include))]
 #[derive(Debug)]
 pub struct Scanner {
-    ips: Vec<IpAddr>,
+    ips: TargetSet,
     batch_size: u16,
     timeout: Duration,
     tries: NonZeroU8,
-    greppable: bool,
     port_strategy: PortStrategy,
-    accessible: bool,
     exclude_ports: Vec<u16>,
     udp: bool,
+    min_batch_size: Option<u16>,
+    rate_limiter: RateLimiter,
+    banner: Option<BannerGrab>,
+    syn: Option<SynScanner>,
+    rtt: Option<RttEstimator>,
+    proxy: Option<ProxyChain>,
+    checkpoint: Option<Checkpointing>,
+    budget: TimeBudget,
+    udp_ports: Vec<u16>,
+    cancellation: Cancellation,
+    transport: Box<dyn Transport>,
 }
 
-// Allowing too many arguments for clippy.
-#[allow(clippy::too_many_arguments)]
 impl Scanner {
-    pub fn new(
-        ips: &[IpAddr],
-        batch_size: u16,
-        timeout: Duration,
-        tries: u8,
-        greppable: bool,
-        port_strategy: PortStrategy,
-        accessible: bool,
-        exclude_ports: Vec<u16>,
-        udp: bool,
-    ) -> Self {
-        Self {
-            batch_size,
-            timeout,
-            tries: NonZeroU8::new(std::cmp::max(tries, 1)).unwrap(),
-            greppable,
-            port_strategy,
-            ips: ips.iter().map(ToOwned::to_owned).collect(),
-            accessible,
-            exclude_ports,
-            udp,
-        }
+    /// Starts building a scanner of the `port_strategy` ports of `ips`.
+    pub fn builder(ips: impl Into<TargetSet>, port_strategy: PortStrategy) -> ScannerBuilder {
+        ScannerBuilder::new(ips.into(), port_strategy)
+    }
+
+    /// A handle stopping the scan from anywhere, such as a signal handler.
+    /// The scan then returns what it found so far.
+    pub fn cancel_handle(&self) -> CancelHandle {
+        self.cancellation.handle()
     }
 
     /// Runs scan_range with chunk sizes
     /// If you want to run RustScan normally, this is the entry point used
-    /// Returns all open ports as `Vec<u16>`
-    pub async fn run(&self) -> Vec<SocketAddr> {
+    /// Returns the state of every scanned socket as a `ScanReport`
+    pub async fn run(&self) -> ScanReport {
+        let mut report = ScanReport::new();
+        self.scan(|event| report.record(&event)).await;
+        debug!(
+            "Open Sockets found: {:?} {:?}",
+            report.open_sockets(Protocol::Tcp),
+            report.open_sockets(Protocol::Udp)
+        );
+        report
+    }
+
+    /// Runs the same scan as `run`, but yields a `ScanEvent` as soon as
+    /// something happens instead of waiting for the whole scan to finish.
+    /// The stream ends once every socket has been scanned, or the scan was
+    /// cut short.
+    ///
+    /// The returned stream is not `Unpin`, pin it before polling.
+    ///
+    /// ```rust
+    /// # use async_std::task::block_on;
+    /// # use futures::StreamExt;
+    /// # use std::{net::IpAddr, time::Duration};
+    /// # use rustscan::input::ScanOrder;
+    /// # use rustscan::port_strategy::PortStrategy;
+    /// # use rustscan::scanner::{ScanEvent, Scanner};
+    /// # let addrs = vec!["127.0.0.1".parse::<IpAddr>().unwrap()];
+    /// # let strategy = PortStrategy::pick(&None, Some(vec![80, 443]), ScanOrder::Serial);
+    /// # let scanner = Scanner::builder(&addrs, strategy).batch_size(10).timeout(Duration::from_millis(100)).build().unwrap();
+    /// block_on(async {
+    ///     let mut events = Box::pin(scanner.stream());
+    ///     while let Some(event) = events.next().await {
+    ///         if let ScanEvent::Open(socket, protocol) = event {
+    ///             println!("{socket}/{protocol} is open");
+    ///         }
+    ///     }
+    /// });
+    /// ```
+    pub fn stream(&self) -> impl Stream<Item = ScanEvent> + '_ {
+        let (sender, receiver) = mpsc::unbounded();
+        let scan = self.scan(move |event| {
+            // The receiver only goes away when the consumer dropped the stream,
+            // which also drops this future, so there is nobody left to tell.
+            let _ = sender.unbounded_send(event);
+        });
+        let driver = stream::once(scan).filter_map(|()| future::ready(None));
+        stream::select(receiver, driver)
+    }
+
+    /// The protocol of the ports of the port strategy.
+    fn protocol(&self) -> Protocol {
+        if self.udp {
+            Protocol::Udp
+        } else {
+            Protocol::Tcp
+        }
+    }
+
+    /// Drives the scan, handing every `ScanEvent` to `emit` as it happens.
+    async fn scan<F: FnMut(ScanEvent)>(&self, mut emit: F) {
         let ports: Vec<u16> = self
             .port_strategy
             .order()
@@ -77,94 +184,305 @@ impl Scanner {
             .filter(|&port| !self.exclude_ports.contains(port))
             .copied()
             .collect();
-        let mut socket_iterator: SocketIterator = SocketIterator::new(&self.ips, &ports);
-        let mut open_sockets: Vec<SocketAddr> = Vec::new();
+        let udp_ports: Vec<u16> = self
+            .udp_ports
+            .iter()
+            .filter(|&port| !self.exclude_ports.contains(port))
+            .copied()
+            .collect();
+        // The extra UDP ports come after the ports of the strategy, which
+        // keeps the order of a checkpoint made by a single protocol scan.
+        let protocol = self.protocol();
+        let sockets = |ports| match self.port_strategy.product_seed() {
+            Some(seed) => SocketIterator::permuted(&self.ips, ports, seed),
+            None => SocketIterator::new(&self.ips, ports),
+        };
+        let mut socket_iterator = sockets(&ports)
+            .map(|socket| (socket, protocol))
+            .chain(sockets(&udp_ports).map(|socket| (socket, Protocol::Udp)));
         let mut ftrs = FuturesUnordered::new();
         let mut errors: HashSet<String> = HashSet::new();
-        let udp_map = get_parsed_data();
 
-        for _ in 0..self.batch_size {
-            if let Some(socket) = socket_iterator.next() {
-                ftrs.push(self.scan_socket(socket, udp_map.clone()));
-            } else {
-                break;
-            }
-        }
+        let hosts = usize::try_from(self.ips.len()).unwrap_or(usize::MAX);
+        let ports_per_ip = ports.len() + udp_ports.len();
+        let total = hosts.saturating_mul(ports_per_ip);
+        let mut scanned = 0;
+        // Only the hosts being scanned are counted, a host is added with its
+        // first socket and dropped with its last one.
+        let mut remaining_per_ip: HashMap<IpAddr, usize> = HashMap::new();
+        let mut hosts_started: u128 = 0;
+
+        let mut congestion = match self.min_batch_size {
+            Some(min_batch_size) => CongestionController::adaptive(min_batch_size, self.batch_size),
+            None => CongestionController::fixed(self.batch_size),
+        };
 
         debug!("Start scanning sockets. \nBatch size {}\nNumber of ip-s {}\nNumber of ports {}\nTargets all together {} ",
             self.batch_size,
-            self.ips.len(),
-            &ports.len(),
-            (self.ips.len() * ports.len()));
+            hosts,
+            ports_per_ip,
+            total);
+
+        // Resuming skips the sockets the checkpoint knows to be done, along
+        // with the hosts that were finished.
+        let mut checkpoint = self
+            .checkpoint
+            .as_ref()
+            .map(|checkpointing| (checkpointing, checkpointing.state.clone(), Instant::now()));
+        if let Some((_, state, _)) = &checkpoint {
+            let mut finished = Vec::new();
+            for (socket, _) in socket_iterator.by_ref().take(state.position) {
+                scanned += 1;
+                let remaining = remaining_per_ip.entry(socket.ip()).or_insert_with(|| {
+                    hosts_started += 1;
+                    ports_per_ip
+                });
+                *remaining -= 1;
+                if *remaining == 0 {
+                    remaining_per_ip.remove(&socket.ip());
+                    finished.push(socket.ip());
+                }
+            }
+            for socket in &state.open {
+                emit(ScanEvent::Open(*socket, protocol));
+            }
+            for socket in &state.open_udp {
+                emit(ScanEvent::Open(*socket, Protocol::Udp));
+            }
+            for ip in finished {
+                emit(ScanEvent::HostFinished(ip));
+            }
+        }
+        // Sockets are numbered in scan order, the checkpoint position is
+        // the first one still in flight.
+        let mut next_index = scanned;
+        let mut in_flight = BTreeSet::new();
+        let mut grace_deadline = None;
+        // Sockets that failed for lack of files, scanned again first.
+        let mut requeued = VecDeque::new();
+        let mut out_of_files = false;
+        let mut clock = BudgetClock::start(self.budget);
+        let scan_deadline = clock.scan_deadline();
+
+        loop {
+            if grace_deadline.is_none() && self.cancellation.is_cancelled() {
+                debug!("Scan cancelled with {} sockets in flight", ftrs.len());
+                grace_deadline = Some(Instant::now() + self.timeout);
+            }
+            if grace_deadline.is_none() && scan_deadline.is_some_and(|d| Instant::now() >= d) {
+                debug!("Scan out of time with {} sockets in flight", ftrs.len());
+                grace_deadline = Some(Instant::now() + self.timeout);
+            }
+
+            // The window may have shrunk below the number of sockets in
+            // flight, in which case nothing new is scheduled until enough
+            // of them finish.
+            while grace_deadline.is_none() && ftrs.len() < congestion.window() {
+                let (index, socket, protocol) = match requeued.pop_front() {
+                    Some(requeued) => requeued,
+                    None => {
+                        let Some((socket, protocol)) = socket_iterator.next() else {
+                            break;
+                        };
+                        let index = next_index;
+                        next_index += 1;
+                        remaining_per_ip.entry(socket.ip()).or_insert_with(|| {
+                            hosts_started += 1;
+                            ports_per_ip
+                        });
+                        // The sockets of a host out of time are dropped,
+                        // which leaves it incomplete.
+                        if !clock.host_has_time(socket.ip()) {
+                            scanned += 1;
+                            if progress_moved(scanned, total) {
+                                emit(ScanEvent::Progress { scanned, total });
+                            }
+                            continue;
+                        }
+                        in_flight.insert(index);
+                        (index, socket, protocol)
+                    }
+                };
+                ftrs.push(
+                    self.scan_socket(socket, protocol)
+                        .map(move |(socket, result)| (index, socket, protocol, result)),
+                );
+            }
+
+            let next = match grace_deadline {
+                None => {
+                    let stop = std::pin::pin!(async {
+                        match scan_deadline {
+                            Some(deadline) => {
+                                let out_of_time = std::pin::pin!(async_std::task::sleep(
+                                    deadline.saturating_duration_since(Instant::now())
+                                ));
+                                let cancelled = std::pin::pin!(self.cancellation.cancelled());
+                                future::select(out_of_time, cancelled).await;
+                            }
+                            None => self.cancellation.cancelled().await,
+                        }
+                    });
+                    match future::select(ftrs.next(), stop).await {
+                        Either::Left((next, _)) => next,
+                        Either::Right(((), _)) => continue,
+                    }
+                }
+                // Sockets still in flight once the grace period is over
+                // are given up.
+                Some(deadline) => {
+                    io::timeout(deadline.saturating_duration_since(Instant::now()), async {
+                        Ok(ftrs.next().await)
+                    })
+                    .await
+                    .unwrap_or(None)
+                }
+            };
+            let Some((index, socket, protocol, result)) = next else {
+                break;
+            };
 
-        while let Some(result) = ftrs.next().await {
-            if let Some(socket) = socket_iterator.next() {
-                ftrs.push(self.scan_socket(socket, udp_map.clone()));
+            // Running out of files says nothing about the socket, it is
+            // scanned again once fewer sockets hold one. When nothing else
+            // holds any, the limit can't be helped and the error stands.
+            if let Err(e) = &result {
+                if is_out_of_files(e) && !ftrs.is_empty() {
+                    out_of_files = true;
+                    congestion.out_of_files(ftrs.len());
+                    requeued.push_back((index, socket, protocol));
+                    continue;
+                }
             }
+            in_flight.remove(&index);
 
             match result {
-                Ok(socket) => open_sockets.push(socket),
+                Ok(banner) => {
+                    congestion.record(PortState::Open);
+                    if let Some(banner) = banner {
+                        emit(ScanEvent::Banner(socket, banner));
+                    }
+                    emit(ScanEvent::Open(socket, protocol));
+                    if let Some((_, state, _)) = &mut checkpoint {
+                        if protocol == self.protocol() {
+                            state.open.push(socket);
+                        } else {
+                            state.open_udp.push(socket);
+                        }
+                    }
+                }
                 Err(e) => {
-                    let error_string = e.to_string();
-                    if errors.len() < self.ips.len() * 1000 {
-                        errors.insert(error_string);
+                    if errors.len() < hosts.saturating_mul(1000) {
+                        errors.insert(format!("{e} {}", socket.ip()));
+                    }
+                    let state = match protocol {
+                        Protocol::Udp => PortState::from_udp_error(&e),
+                        Protocol::Tcp => PortState::from_error(&e),
+                    };
+                    congestion.record(state);
+                    emit(ScanEvent::Closed(socket, protocol, state));
+                }
+            }
+
+            if let Some(remaining) = remaining_per_ip.get_mut(&socket.ip()) {
+                *remaining -= 1;
+                if *remaining == 0 {
+                    remaining_per_ip.remove(&socket.ip());
+                    clock.host_finished(socket.ip());
+                    if let (Some(syn), IpAddr::V4(ip)) = (&self.syn, socket.ip()) {
+                        syn.host_finished(ip);
                     }
+                    emit(ScanEvent::HostFinished(socket.ip()));
+                }
+            }
+
+            scanned += 1;
+            if progress_moved(scanned, total) {
+                emit(ScanEvent::Progress { scanned, total });
+            }
+
+            if let Some((checkpointing, state, saved)) = &mut checkpoint {
+                if saved.elapsed() >= checkpointing.interval {
+                    state.position = in_flight.first().copied().unwrap_or(next_index);
+                    checkpointing.save(state);
+                    *saved = Instant::now();
                 }
             }
         }
+
+        if let Some((checkpointing, state, _)) = &mut checkpoint {
+            state.position = in_flight.first().copied().unwrap_or(next_index);
+            checkpointing.save(state);
+        }
+        // Hosts with sockets left are incomplete. The ones the scan never got
+        // to are only counted, a range may hold too many of them to name.
+        let incomplete: BTreeSet<IpAddr> = remaining_per_ip.into_keys().collect();
+        for ip in incomplete {
+            emit(ScanEvent::HostIncomplete(ip));
+        }
+        if ports_per_ip > 0 && hosts_started < self.ips.len() {
+            emit(ScanEvent::HostsNotReached(self.ips.len() - hosts_started));
+        }
+        if out_of_files {
+            emit(ScanEvent::OutOfFiles(congestion.max()));
+        }
         debug!("Typical socket connection errors {:?}", errors);
-        debug!("Open Sockets found: {:?}", &open_sockets);
-        open_sockets
+        debug!("Batch size at the end of the scan {}", congestion.window());
     }
 
     /// Given a socket, scan it self.tries times.
     /// Turns the address into a SocketAddr
     /// Deals with the `<result>` type
-    /// If it experiences error ErrorKind::Other then too many files are open and it Panics!
-    /// Else any other error, it returns the error in Result as a string
-    /// If no errors occur, it returns the port number in Result to signify the port is open.
+    /// If the process runs out of files it returns the error right away, without
+    /// retrying, so the caller can scan the socket again later.
+    /// Else any other error, it returns the error of the last try untouched so
+    /// the caller can classify it into a `PortState`.
+    /// If no errors occur, it returns `Ok` alongside the socket to signify the port is open,
+    /// holding the banner of the service when banner grabbing is enabled.
     /// This function mainly deals with the logic of Results handling.
     /// # Example
     ///
     /// ```compile_fail
-    /// scanner.scan_socket(socket)
+    /// scanner.scan_socket(socket, Protocol::Tcp)
     /// ```
     ///
     /// Note: `self` must contain `self.ip`.
     async fn scan_socket(
         &self,
         socket: SocketAddr,
-        udp_map: BTreeMap<Vec<u16>, Vec<u8>>,
-    ) -> io::Result<SocketAddr> {
-        if self.udp {
-            return self.scan_udp_socket(socket, udp_map).await;
+        protocol: Protocol,
+    ) -> (SocketAddr, io::Result<Option<Banner>>) {
+        if protocol == Protocol::Udp {
+            let result = self.scan_udp_socket(socket).await;
+            return (socket, result.map(|()| None));
+        }
+
+        if let (Some(syn), SocketAddr::V4(target)) = (&self.syn, socket) {
+            return (socket, self.syn_scan_socket(syn, target).await);
         }
 
         let tries = self.tries.get();
         for nr_try in 1..=tries {
             match self.connect(socket).await {
-                Ok(tcp_stream) => {
+                Ok(mut stream) => {
+                    let banner = match self.banner {
+                        Some(options) => banner::grab(&mut stream, options).await,
+                        None => None,
+                    };
+
                     debug!(
                         "Connection was successful, shutting down stream {}",
                         &socket
                     );
-                    if let Err(e) = tcp_stream.shutdown(Shutdown::Both) {
+                    if let Err(e) = stream.close().await {
                         debug!("Shutdown stream error {}", &e);
                     }
-                    self.fmt_ports(socket);
 
                     debug!("Return Ok after {} tries", nr_try);
-                    return Ok(socket);
+                    return (socket, Ok(banner));
                 }
                 Err(e) => {
-                    let mut error_string = e.to_string();
-
-                    assert!(!error_string.to_lowercase().contains("too many open files"), "Too many open files. Please reduce batch size. The default is 5000. Try -b 2500.");
-
-                    if nr_try == tries {
-                        error_string.push(' ');
-                        error_string.push_str(&socket.ip().to_string());
-                        return Err(io::Error::new(io::ErrorKind::Other, error_string));
+                    if nr_try == tries || is_out_of_files(&e) {
+                        return (socket, Err(e));
                     }
                 }
             };
@@ -172,30 +490,56 @@ impl Scanner {
         unreachable!();
     }
 
-    async fn scan_udp_socket(
+    /// Scans a socket with half-open SYN probes, retrying as long as they
+    /// time out.
+    async fn syn_scan_socket(
         &self,
-        socket: SocketAddr,
-        udp_map: BTreeMap<Vec<u16>, Vec<u8>>,
-    ) -> io::Result<SocketAddr> {
-        let mut payload: Vec<u8> = Vec::new();
-        for (key, value) in udp_map {
-            if key.contains(&socket.port()) {
-                payload = value;
+        syn: &SynScanner,
+        target: SocketAddrV4,
+    ) -> io::Result<Option<Banner>> {
+        let tries = self.tries.get();
+        for nr_try in 1..=tries {
+            let ip = IpAddr::V4(*target.ip());
+            self.rate_limiter.acquire(ip).await;
+            let started = Instant::now();
+            let result = syn.probe(target, self.probe_timeout(ip)).await;
+            self.record_rtt(ip, started, &result);
+            match result {
+                Ok(()) => {
+                    debug!("Return Ok after {} tries", nr_try);
+                    return Ok(None);
+                }
+                Err(e) if e.kind() == io::ErrorKind::TimedOut && nr_try < tries => {}
+                Err(e) => return Err(e),
             }
         }
+        unreachable!();
+    }
+
+    /// Scans a UDP socket with every payload meant for its port, or an empty
+    /// datagram when there is none. An ICMP port unreachable, seen as a
+    /// refused connection, tells the port is closed and ends the tries.
+    async fn scan_udp_socket(&self, socket: SocketAddr) -> io::Result<()> {
+        let payloads = match udp::payloads(socket.port()) {
+            [] => &[&[][..]],
+            payloads => payloads,
+        };
 
         let tries = self.tries.get();
         for _ in 1..=tries {
-            match self.udp_scan(socket, &payload, self.timeout).await {
-                Ok(true) => return Ok(socket),
+            match self
+                .udp_scan(socket, payloads, self.probe_timeout(socket.ip()))
+                .await
+            {
+                Ok(true) => return Ok(()),
                 Ok(false) => continue,
                 Err(e) => return Err(e),
             }
         }
 
         Err(io::Error::new(
-            io::ErrorKind::Other,
-            format!("UDP scan timed-out for all tries on socket {}", socket),
+            io::ErrorKind::TimedOut,
+            format!("UDP scan timed-out for all tries on socket {socket}"),
         ))
     }
 
@@ -210,42 +554,48 @@ impl Scanner {
     /// let socket = SocketAddr::new(ip, port);
     /// scanner.connect(socket);
     /// // returns Result which is either Ok(stream) for port is open, or Er for port is closed.
-    /// // Timeout occurs after self.timeout seconds
+    /// // Timeout occurs after the probe timeout of the host, not counting the wait for the rate limiter
     /// ```
     ///
-    async fn connect(&self, socket: SocketAddr) -> io::Result<TcpStream> {
-        let stream = io::timeout(
-            self.timeout,
-            async move { TcpStream::connect(socket).await },
-        )
-        .await?;
-        Ok(stream)
+    async fn connect(&self, socket: SocketAddr) -> io::Result<Box<dyn Connection>> {
+        self.rate_limiter.acquire(socket.ip()).await;
+        let started = Instant::now();
+        let result = io::timeout(self.probe_timeout(socket.ip()), async {
+            match &self.proxy {
+                Some(proxy) => proxy.connect(socket, self.transport.as_ref()).await,
+                None => self.transport.connect(socket).await,
+            }
+        })
+        .await;
+        self.record_rtt(socket.ip(), started, &result);
+        result
     }
 
-    /// Binds to a UDP socket so we can send and receive packets
-    /// # Example
-    ///
-    /// ```compile_fail
-    /// # use std::net::{IpAddr, Ipv6Addr, SocketAddr};
-    /// let port: u16 = 80;
-    /// // ip is an IpAddr type
-    /// let ip = IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1));
-    /// let socket = SocketAddr::new(ip, port);
-    /// scanner.udp_bind(socket);
-    /// // returns Result which is either Ok(stream) for port is open, or Err for port is closed.
-    /// // Timeout occurs after self.timeout seconds
-    /// ```
-    ///
-    async fn udp_bind(&self, socket: SocketAddr) -> io::Result<UdpSocket> {
-        let local_addr = match socket {
-            SocketAddr::V4(_) => "0.0.0.0:0".parse::<SocketAddr>().unwrap(),
-            SocketAddr::V6(_) => "[::]:0".parse::<SocketAddr>().unwrap(),
-        };
+    /// How long to wait for `ip` to answer a probe, the fixed timeout unless
+    /// it is derived from round trip times.
+    fn probe_timeout(&self, ip: IpAddr) -> Duration {
+        self.rtt
+            .as_ref()
+            .map_or(self.timeout, |rtt| rtt.timeout(ip))
+    }
 
-        UdpSocket::bind(local_addr).await
+    /// Feeds the round trip time of a probe to the estimator, when the host
+    /// answered it by accepting or refusing the connection.
+    fn record_rtt<T>(&self, ip: IpAddr, started: Instant, result: &io::Result<T>) {
+        let Some(rtt) = &self.rtt else {
+            return;
+        };
+        let answered = match result {
+            Ok(_) => true,
+            Err(e) => e.kind() == io::ErrorKind::ConnectionRefused,
+        };
+        if answered {
+            rtt.record(ip, started.elapsed());
+        }
     }
 
-    /// Performs a UDP scan on the specified socket with a payload and wait duration
+    /// Performs a UDP scan on the specified socket, sending every payload at
+    /// once through the transport and waiting for an answer to any of them
     /// # Example
     ///
     /// ```compile_fail
@@ -255,56 +605,45 @@ impl Scanner {
     /// // ip is an IpAddr type
     /// let ip = IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1));
     /// let socket = SocketAddr::new(ip, port);
-    /// let payload = vec![0, 1, 2, 3];
+    /// let payloads: Vec<&[u8]> = vec![&[0, 1], &[2, 3]];
     /// let wait = Duration::from_secs(1);
-    /// let result = scanner.udp_scan(socket, payload, wait).await;
+    /// let result = scanner.udp_scan(socket, &payloads, wait).await;
     /// // returns Result which is either Ok(true) if response received, or Ok(false) if timed out.
-    /// // Err is returned for other I/O errors.
+    /// // Err is returned for other I/O errors, ConnectionRefused when the port is closed.
     async fn udp_scan(
         &self,
         socket: SocketAddr,
-        payload: &[u8],
+        payloads: &[&[u8]],
         wait: Duration,
     ) -> io::Result<bool> {
-        match self.udp_bind(socket).await {
-            Ok(udp_socket) => {
-                let mut buf = [0u8; 1024];
-
-                udp_socket.connect(socket).await?;
-                udp_socket.send(payload).await?;
-
-                match io::timeout(wait, udp_socket.recv(&mut buf)).await {
-                    Ok(size) => {
-                        debug!("Received {} bytes", size);
-                        self.fmt_ports(socket);
-                        Ok(true)
-                    }
-                    Err(e) => {
-                        if e.kind() == io::ErrorKind::TimedOut {
-                            Ok(false)
-                        } else {
-                            Err(e)
-                        }
-                    }
-                }
-            }
-            Err(e) => {
-                println!("Err E binding sock {:?}", e);
-                Err(e)
-            }
+        for _ in payloads {
+            self.rate_limiter.acquire(socket.ip()).await;
         }
+        self.transport.probe_udp(socket, payloads, wait).await
     }
+}
 
-    /// Formats and prints the port status
-    fn fmt_ports(&self, socket: SocketAddr) {
-        if !self.greppable {
-            if self.accessible {
-                println!("Open {socket}");
-            } else {
-                println!("Open {}", socket.to_string().purple());
-            }
-        }
-    }
+/// Whether the completed percentage moved with the last scanned socket.
+/// Progress is only reported then, a full range scan would otherwise double
+/// the number of events.
+fn progress_moved(scanned: usize, total: usize) -> bool {
+    scanned == total || (scanned * 100 / total) != ((scanned - 1) * 100 / total)
+}
+
+/// Whether the process or the whole system ran out of file descriptors,
+/// EMFILE and ENFILE.
+fn is_out_of_files(error: &io::Error) -> bool {
+    #[cfg(unix)]
+    let codes = [libc::EMFILE, libc::ENFILE];
+    // WSAEMFILE, Windows has no system wide limit.
+    #[cfg(windows)]
+    let codes = [10024];
+    #[cfg(not(any(unix, windows)))]
+    let codes: [i32; 0] = [];
+
+    error
+        .raw_os_error()
+        .is_some_and(|code| codes.contains(&code))
 }
 
 #[cfg(test)]
@@ -312,7 +651,11 @@ mod tests {
     use super::*;
     use crate::input::{PortRange, ScanOrder};
     use async_std::task::block_on;
-    use std::{net::IpAddr, time::Duration};
+    use cidr_utils::cidr::IpCidr;
+    use std::{
+        net::{IpAddr, SocketAddr},
+        time::Duration,
+    };
 
     #[test]
     fn scanner_runs() {
@@ -323,22 +666,179 @@ mod tests {
             end: 1_000,
         };
         let strategy = PortStrategy::pick(&Some(range), None, ScanOrder::Random);
-        let scanner = Scanner::new(
-            &addrs,
-            10,
-            Duration::from_millis(100),
-            1,
-            true,
-            strategy,
-            true,
-            vec![9000],
-            false,
-        );
+        let scanner = Scanner::builder(&addrs, strategy)
+            .batch_size(10)
+            .timeout(Duration::from_millis(100))
+            .exclude_ports(vec![9000])
+            .build()
+            .unwrap();
         block_on(scanner.run());
         // if the scan fails, it wouldn't be able to assert_eq! as it panicked!
         assert_eq!(1, 1);
     }
     #[test]
+    fn scanner_reports_open_and_closed_ports() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let open_port = listener.local_addr().unwrap().port();
+        // Binding and dropping a listener gives us a port nothing listens on.
+        let closed_port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+
+        let addrs = vec!["127.0.0.1".parse::<IpAddr>().unwrap()];
+        let strategy =
+            PortStrategy::pick(&None, Some(vec![open_port, closed_port]), ScanOrder::Serial);
+        let scanner = Scanner::builder(&addrs, strategy)
+            .batch_size(10)
+            .timeout(Duration::from_millis(500))
+            .build()
+            .unwrap();
+        let report = block_on(scanner.run());
+
+        assert_eq!(
+            report.state(SocketAddr::new(addrs[0], open_port), Protocol::Tcp),
+            Some(PortState::Open)
+        );
+        assert_eq!(
+            report.state(SocketAddr::new(addrs[0], closed_port), Protocol::Tcp),
+            Some(PortState::Closed)
+        );
+    }
+    #[test]
+    fn permuted_scan_goes_through_every_host_and_port() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let open = listener.local_addr().unwrap();
+        let addrs = vec![open.ip(), "127.0.0.2".parse::<IpAddr>().unwrap()];
+        let mut ports = vec![open.port()];
+        ports.extend(1..=50);
+        let strategy = PortStrategy::pick(&None, Some(ports), ScanOrder::Permuted);
+        let scanner = Scanner::builder(&addrs, strategy)
+            .batch_size(10)
+            .timeout(Duration::from_millis(500))
+            .build()
+            .unwrap();
+        let report = block_on(scanner.run());
+
+        assert_eq!(report.open_sockets(Protocol::Tcp), vec![open]);
+        for ip in &addrs {
+            assert_eq!(report.summary(*ip).total(), 51);
+        }
+        assert_eq!(report.incomplete_hosts().count(), 0);
+    }
+    #[test]
+    fn stream_yields_events_while_scanning() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let open_port = listener.local_addr().unwrap().port();
+
+        let addrs = vec!["127.0.0.1".parse::<IpAddr>().unwrap()];
+        let strategy = PortStrategy::pick(&None, Some(vec![open_port]), ScanOrder::Serial);
+        let scanner = Scanner::builder(&addrs, strategy)
+            .batch_size(10)
+            .timeout(Duration::from_millis(500))
+            .build()
+            .unwrap();
+        let events: Vec<ScanEvent> = block_on(scanner.stream().collect());
+
+        assert_eq!(
+            events,
+            vec![
+                ScanEvent::Open(SocketAddr::new(addrs[0], open_port), Protocol::Tcp),
+                ScanEvent::HostFinished(addrs[0]),
+                ScanEvent::Progress {
+                    scanned: 1,
+                    total: 1
+                },
+            ]
+        );
+    }
+    #[test]
+    fn adaptive_scanner_runs() {
+        let addrs = vec!["127.0.0.1".parse::<IpAddr>().unwrap()];
+        let range = PortRange {
+            start: 1,
+            end: 1_000,
+        };
+        let strategy = PortStrategy::pick(&Some(range), None, ScanOrder::Random);
+        let scanner = Scanner::builder(&addrs, strategy)
+            .batch_size(100)
+            .timeout(Duration::from_millis(100))
+            .exclude_ports(vec![9000])
+            .min_batch_size(10)
+            .build()
+            .unwrap();
+        let report = block_on(scanner.run());
+        assert_eq!(report.summary(addrs[0]).total(), 1_000);
+    }
+    #[test]
+    fn rate_limited_scanner_spaces_out_probes() {
+        let addrs = vec!["127.0.0.1".parse::<IpAddr>().unwrap()];
+        let range = PortRange { start: 1, end: 5 };
+        let strategy = PortStrategy::pick(&Some(range), None, ScanOrder::Serial);
+        let scanner = Scanner::builder(&addrs, strategy)
+            .batch_size(10)
+            .timeout(Duration::from_millis(100))
+            .rate_limiter(RateLimiter::new(Some(20), None))
+            .build()
+            .unwrap();
+        let start = std::time::Instant::now();
+        block_on(scanner.run());
+        // Five probes at 20 per second, the last one may only leave after 200ms.
+        assert!(start.elapsed() >= Duration::from_millis(200));
+    }
+    #[test]
+    fn rtt_scanner_shortens_timeout_of_fast_hosts() {
+        let addrs = vec!["127.0.0.1".parse::<IpAddr>().unwrap()];
+        let range = PortRange { start: 1, end: 50 };
+        let strategy = PortStrategy::pick(&Some(range), None, ScanOrder::Serial);
+        let bounds = RttTimeouts {
+            min: Duration::from_millis(50),
+            max: Duration::from_millis(2_000),
+        };
+        let scanner = Scanner::builder(&addrs, strategy)
+            .batch_size(10)
+            .timeout(Duration::from_millis(1_500))
+            .rtt_timeouts(bounds)
+            .build()
+            .unwrap();
+        assert_eq!(
+            scanner.probe_timeout(addrs[0]),
+            Duration::from_millis(1_500)
+        );
+
+        block_on(scanner.run());
+        // Loopback answers in well under a millisecond.
+        assert_eq!(scanner.probe_timeout(addrs[0]), bounds.min);
+    }
+    #[test]
+    fn scanner_attaches_banners_to_open_sockets() {
+        use std::io::Write;
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let open_port = listener.local_addr().unwrap().port();
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.write_all(b"220 smtp.example.com ESMTP\r\n").unwrap();
+        });
+
+        let addrs = vec!["127.0.0.1".parse::<IpAddr>().unwrap()];
+        let strategy = PortStrategy::pick(&None, Some(vec![open_port]), ScanOrder::Serial);
+        let scanner = Scanner::builder(&addrs, strategy)
+            .batch_size(10)
+            .timeout(Duration::from_millis(500))
+            .banner(BannerGrab {
+                wait: Duration::from_millis(500),
+                probe: false,
+            })
+            .build()
+            .unwrap();
+        let report = block_on(scanner.run());
+        let banner = report.banner(SocketAddr::new(addrs[0], open_port)).unwrap();
+
+        assert_eq!(banner.to_string(), "220 smtp.example.com ESMTP");
+    }
+    #[test]
     fn ipv6_scanner_runs() {
         // Makes sure the program still runs and doesn't panic
         let addrs = vec!["::1".parse::<IpAddr>().unwrap()];
@@ -347,17 +847,12 @@ mod tests {
             end: 1_000,
         };
         let strategy = PortStrategy::pick(&Some(range), None, ScanOrder::Random);
-        let scanner = Scanner::new(
-            &addrs,
-            10,
-            Duration::from_millis(100),
-            1,
-            true,
-            strategy,
-            true,
-            vec![9000],
-            false,
-        );
+        let scanner = Scanner::builder(&addrs, strategy)
+            .batch_size(10)
+            .timeout(Duration::from_millis(100))
+            .exclude_ports(vec![9000])
+            .build()
+            .unwrap();
         block_on(scanner.run());
         // if the scan fails, it wouldn't be able to assert_eq! as it panicked!
         assert_eq!(1, 1);
@@ -370,17 +865,12 @@ mod tests {
             end: 1_000,
         };
         let strategy = PortStrategy::pick(&Some(range), None, ScanOrder::Random);
-        let scanner = Scanner::new(
-            &addrs,
-            10,
-            Duration::from_millis(100),
-            1,
-            true,
-            strategy,
-            true,
-            vec![9000],
-            false,
-        );
+        let scanner = Scanner::builder(&addrs, strategy)
+            .batch_size(10)
+            .timeout(Duration::from_millis(100))
+            .exclude_ports(vec![9000])
+            .build()
+            .unwrap();
         block_on(scanner.run());
         assert_eq!(1, 1);
     }
@@ -392,17 +882,12 @@ mod tests {
             end: 445,
         };
         let strategy = PortStrategy::pick(&Some(range), None, ScanOrder::Random);
-        let scanner = Scanner::new(
-            &addrs,
-            10,
-            Duration::from_millis(100),
-            1,
-            true,
-            strategy,
-            true,
-            vec![9000],
-            false,
-        );
+        let scanner = Scanner::builder(&addrs, strategy)
+            .batch_size(10)
+            .timeout(Duration::from_millis(100))
+            .exclude_ports(vec![9000])
+            .build()
+            .unwrap();
         block_on(scanner.run());
         assert_eq!(1, 1);
     }
@@ -417,17 +902,12 @@ mod tests {
             end: 600,
         };
         let strategy = PortStrategy::pick(&Some(range), None, ScanOrder::Random);
-        let scanner = Scanner::new(
-            &addrs,
-            10,
-            Duration::from_millis(100),
-            1,
-            true,
-            strategy,
-            true,
-            vec![9000],
-            false,
-        );
+        let scanner = Scanner::builder(&addrs, strategy)
+            .batch_size(10)
+            .timeout(Duration::from_millis(100))
+            .exclude_ports(vec![9000])
+            .build()
+            .unwrap();
         block_on(scanner.run());
         assert_eq!(1, 1);
     }
@@ -441,17 +921,13 @@ mod tests {
             end: 1_000,
         };
         let strategy = PortStrategy::pick(&Some(range), None, ScanOrder::Random);
-        let scanner = Scanner::new(
-            &addrs,
-            10,
-            Duration::from_millis(100),
-            1,
-            true,
-            strategy,
-            true,
-            vec![9000],
-            true,
-        );
+        let scanner = Scanner::builder(&addrs, strategy)
+            .batch_size(10)
+            .timeout(Duration::from_millis(100))
+            .exclude_ports(vec![9000])
+            .udp(true)
+            .build()
+            .unwrap();
         block_on(scanner.run());
         // if the scan fails, it wouldn't be able to assert_eq! as it panicked!
         assert_eq!(1, 1);
@@ -465,17 +941,13 @@ mod tests {
             end: 1_000,
         };
         let strategy = PortStrategy::pick(&Some(range), None, ScanOrder::Random);
-        let scanner = Scanner::new(
-            &addrs,
-            10,
-            Duration::from_millis(100),
-            1,
-            true,
-            strategy,
-            true,
-            vec![9000],
-            true,
-        );
+        let scanner = Scanner::builder(&addrs, strategy)
+            .batch_size(10)
+            .timeout(Duration::from_millis(100))
+            .exclude_ports(vec![9000])
+            .udp(true)
+            .build()
+            .unwrap();
         block_on(scanner.run());
         // if the scan fails, it wouldn't be able to assert_eq! as it panicked!
         assert_eq!(1, 1);
@@ -488,17 +960,13 @@ mod tests {
             end: 1_000,
         };
         let strategy = PortStrategy::pick(&Some(range), None, ScanOrder::Random);
-        let scanner = Scanner::new(
-            &addrs,
-            10,
-            Duration::from_millis(100),
-            1,
-            true,
-            strategy,
-            true,
-            vec![9000],
-            true,
-        );
+        let scanner = Scanner::builder(&addrs, strategy)
+            .batch_size(10)
+            .timeout(Duration::from_millis(100))
+            .exclude_ports(vec![9000])
+            .udp(true)
+            .build()
+            .unwrap();
         block_on(scanner.run());
         assert_eq!(1, 1);
     }
@@ -510,18 +978,475 @@ mod tests {
             end: 150,
         };
         let strategy = PortStrategy::pick(&Some(range), None, ScanOrder::Random);
-        let scanner = Scanner::new(
-            &addrs,
-            10,
-            Duration::from_millis(100),
-            1,
-            true,
-            strategy,
-            true,
-            vec![9000],
-            true,
-        );
+        let scanner = Scanner::builder(&addrs, strategy)
+            .batch_size(10)
+            .timeout(Duration::from_millis(100))
+            .exclude_ports(vec![9000])
+            .udp(true)
+            .build()
+            .unwrap();
         block_on(scanner.run());
         assert_eq!(1, 1);
     }
+
+    /// A scanner going through `ports` of `addrs` in order, with `options`
+    /// setting what the test is about on top of a small batch size.
+    fn serial_scanner(
+        addrs: impl Into<TargetSet>,
+        ports: Vec<u16>,
+        options: impl FnOnce(ScannerBuilder) -> ScannerBuilder,
+    ) -> Scanner {
+        let builder = Scanner::builder(
+            addrs,
+            PortStrategy::pick(&None, Some(ports), ScanOrder::Serial),
+        )
+        .batch_size(10);
+        options(builder).build().unwrap()
+    }
+
+    fn closed_port() -> u16 {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().port()
+    }
+
+    #[test]
+    fn checkpoint_records_progress() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let open = listener.local_addr().unwrap();
+        let path = std::env::temp_dir().join(format!("rustscan-progress-{}", std::process::id()));
+        let state = Checkpoint {
+            args: vec!["rustscan".to_owned()],
+            seed: 7,
+            ips: TargetSet::from(&[open.ip()]),
+            position: 0,
+            open: vec![],
+            open_udp: vec![],
+        };
+
+        let scanner = serial_scanner(
+            &["127.0.0.1".parse::<IpAddr>().unwrap()],
+            vec![closed_port(), open.port(), closed_port()],
+            |builder| {
+                builder
+                    .timeout(Duration::from_millis(500))
+                    .checkpoint(Checkpointing {
+                        path: path.clone(),
+                        interval: Duration::ZERO,
+                        state: state.clone(),
+                    })
+            },
+        );
+        block_on(scanner.run());
+        let saved = Checkpoint::read(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            saved.unwrap(),
+            Checkpoint {
+                position: 3,
+                open: vec![open],
+                open_udp: vec![],
+                ..state
+            }
+        );
+    }
+
+    #[test]
+    fn resumed_scan_skips_done_sockets() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let open = listener.local_addr().unwrap();
+        // Closed now, but found open before the interruption.
+        let done = SocketAddr::new(open.ip(), closed_port());
+        let path = std::env::temp_dir().join(format!("rustscan-resumed-{}", std::process::id()));
+
+        let scanner = serial_scanner(
+            &["127.0.0.1".parse::<IpAddr>().unwrap()],
+            vec![done.port(), open.port()],
+            |builder| {
+                builder
+                    .timeout(Duration::from_millis(500))
+                    .checkpoint(Checkpointing {
+                        path: path.clone(),
+                        interval: Duration::from_secs(60),
+                        state: Checkpoint {
+                            args: vec!["rustscan".to_owned()],
+                            seed: 7,
+                            ips: TargetSet::from(&[open.ip()]),
+                            position: 1,
+                            open: vec![done],
+                            open_udp: vec![],
+                        },
+                    })
+            },
+        );
+        let report = block_on(scanner.run());
+        let saved = Checkpoint::read(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        let mut expected = vec![done, open];
+        expected.sort();
+        assert_eq!(report.open_sockets(Protocol::Tcp), expected);
+        assert_eq!(report.summary(open.ip()).total(), 2);
+        assert_eq!(saved.unwrap().position, 2);
+    }
+
+    #[test]
+    fn cancelled_scan_returns_what_it_found() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let open = listener.local_addr().unwrap();
+        let addrs = vec![open.ip()];
+        let mut ports = vec![open.port()];
+        ports.extend(1..=1_000);
+        let scanner = Scanner::builder(
+            &addrs,
+            PortStrategy::pick(&None, Some(ports), ScanOrder::Serial),
+        )
+        .batch_size(10)
+        .timeout(Duration::from_millis(500))
+        .rate_limiter(RateLimiter::new(Some(20), None))
+        .build()
+        .unwrap();
+
+        let handle = scanner.cancel_handle();
+        std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(300));
+            handle.cancel();
+        });
+        let started = Instant::now();
+        let report = block_on(scanner.run());
+
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(report.open_sockets(Protocol::Tcp), vec![open]);
+        assert!(report.summary(open.ip()).total() < 100);
+    }
+
+    #[test]
+    fn host_out_of_time_is_incomplete() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let open = listener.local_addr().unwrap();
+        let mut ports = vec![open.port()];
+        ports.extend(1..=1_000);
+        let scanner = serial_scanner(&[open.ip()], ports, |builder| {
+            builder
+                .timeout(Duration::from_millis(500))
+                .rate_limiter(RateLimiter::new(Some(20), None))
+                .budget(TimeBudget {
+                    host: Some(Duration::from_millis(300)),
+                    scan: None,
+                })
+        });
+
+        let started = Instant::now();
+        let report = block_on(scanner.run());
+
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(report.open_sockets(Protocol::Tcp), vec![open]);
+        assert!(report.summary(open.ip()).total() < 100);
+        assert!(report.is_incomplete(open.ip()));
+    }
+
+    #[test]
+    fn dropped_sockets_still_report_progress() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let open = listener.local_addr().unwrap();
+        let mut ports = vec![open.port()];
+        ports.extend(1..=1_000);
+        let scanner = serial_scanner(&[open.ip()], ports, |builder| {
+            builder
+                .timeout(Duration::from_millis(500))
+                .rate_limiter(RateLimiter::new(Some(20), None))
+                .budget(TimeBudget {
+                    host: Some(Duration::from_millis(300)),
+                    scan: None,
+                })
+        });
+
+        let progress: Vec<(usize, usize)> = block_on(
+            scanner
+                .stream()
+                .filter_map(|event| async move {
+                    match event {
+                        ScanEvent::Progress { scanned, total } => Some((scanned, total)),
+                        _ => None,
+                    }
+                })
+                .collect(),
+        );
+
+        // Every percent is reported, up to the last socket.
+        assert_eq!(progress.len(), 100);
+        assert_eq!(progress.last(), Some(&(1_001, 1_001)));
+    }
+
+    #[test]
+    fn scan_out_of_time_keeps_what_it_found() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let open = listener.local_addr().unwrap();
+        let addrs = vec![open.ip(), "127.0.0.2".parse::<IpAddr>().unwrap()];
+        let mut ports = vec![open.port()];
+        ports.extend(1..=1_000);
+        let scanner = serial_scanner(&addrs, ports, |builder| {
+            builder
+                .timeout(Duration::from_millis(500))
+                .rate_limiter(RateLimiter::new(Some(20), None))
+                .budget(TimeBudget {
+                    host: None,
+                    scan: Some(Duration::from_millis(300)),
+                })
+        });
+
+        let started = Instant::now();
+        let report = block_on(scanner.run());
+
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(report.open_sockets(Protocol::Tcp), vec![open]);
+        assert_eq!(
+            report.incomplete_hosts().collect::<Vec<_>>(),
+            vec![&addrs[0], &addrs[1]]
+        );
+    }
+
+    #[test]
+    fn hosts_not_reached_are_counted() {
+        let mut ips = TargetSet::new();
+        ips.insert_cidr(&"10.0.0.0/8".parse::<IpCidr>().unwrap());
+        let scanner = serial_scanner(ips, vec![80], |builder| {
+            builder
+                .timeout(Duration::from_millis(500))
+                .rate_limiter(RateLimiter::new(Some(20), None))
+                .budget(TimeBudget {
+                    host: None,
+                    scan: Some(Duration::ZERO),
+                })
+        });
+
+        let started = Instant::now();
+        let report = block_on(scanner.run());
+
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(report.incomplete_hosts().count(), 0);
+        assert_eq!(report.hosts_not_reached(), 1 << 24);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn recognizes_running_out_of_files() {
+        assert!(is_out_of_files(&io::Error::from_raw_os_error(libc::EMFILE)));
+        assert!(is_out_of_files(&io::Error::from_raw_os_error(libc::ENFILE)));
+        assert!(!is_out_of_files(&io::Error::from_raw_os_error(
+            libc::ECONNREFUSED
+        )));
+        assert!(!is_out_of_files(&io::Error::other("too many open files")));
+    }
+
+    #[test]
+    fn udp_states() {
+        let answering = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        answering
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+        let open = answering.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let mut buf = [0; 64];
+            while let Ok((_, peer)) = answering.recv_from(&mut buf) {
+                answering.send_to(b"hi", peer).unwrap();
+            }
+        });
+        let silent = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let open_filtered = silent.local_addr().unwrap();
+        let closed = {
+            let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+            socket.local_addr().unwrap()
+        };
+
+        let scan = |target: SocketAddr, tries| {
+            let scanner = serial_scanner(&[target.ip()], vec![target.port()], |builder| {
+                builder
+                    .timeout(Duration::from_millis(300))
+                    .tries(tries)
+                    .udp(true)
+            });
+            block_on(scanner.run())
+        };
+
+        let report = scan(open, 1);
+        assert_eq!(report.state(open, Protocol::Udp), Some(PortState::Open));
+        let report = scan(open_filtered, 1);
+        assert_eq!(
+            report.state(open_filtered, Protocol::Udp),
+            Some(PortState::OpenFiltered)
+        );
+
+        // A closed port answers right away, the other tries are not needed.
+        let started = Instant::now();
+        let report = scan(closed, 5);
+        assert_eq!(report.state(closed, Protocol::Udp), Some(PortState::Closed));
+        assert!(started.elapsed() < Duration::from_millis(300));
+    }
+
+    #[test]
+    fn scans_tcp_and_udp_ports_together() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let tcp = listener.local_addr().unwrap();
+        let answering = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        answering
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+        let udp = answering.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let mut buf = [0; 64];
+            while let Ok((_, peer)) = answering.recv_from(&mut buf) {
+                answering.send_to(b"hi", peer).unwrap();
+            }
+        });
+
+        let scanner = Scanner::builder(
+            &[tcp.ip()],
+            PortStrategy::pick(&None, Some(vec![tcp.port(), udp.port()]), ScanOrder::Serial),
+        )
+        .batch_size(10)
+        .timeout(Duration::from_millis(300))
+        .udp_ports(vec![tcp.port(), udp.port()])
+        .build()
+        .unwrap();
+        let report = block_on(scanner.run());
+
+        assert_eq!(report.open_sockets(Protocol::Tcp), vec![tcp]);
+        assert_eq!(report.open_sockets(Protocol::Udp), vec![udp]);
+        assert_eq!(report.summary(tcp.ip()).total(), 4);
+    }
+
+    #[test]
+    fn simulated_sockets_are_classified() {
+        let network = SimulatedNetwork::new();
+        let socket = |port| SocketAddr::new("10.0.0.1".parse().unwrap(), port);
+        for protocol in [Protocol::Tcp, Protocol::Udp] {
+            network.script(protocol, socket(1), [SocketBehavior::Open]);
+            network.script(protocol, socket(2), [SocketBehavior::Refused]);
+            network.script(protocol, socket(3), [SocketBehavior::Drop]);
+        }
+
+        let scanner = serial_scanner(&[socket(1).ip()], vec![1, 2, 3], |builder| {
+            builder
+                .timeout(Duration::from_millis(50))
+                .tries(1)
+                .udp_ports(vec![1, 2, 3])
+                .transport(network.clone())
+        });
+        let report = block_on(scanner.run());
+
+        let states = |protocol| {
+            (1..=3)
+                .map(|port| report.state(socket(port), protocol))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            states(Protocol::Tcp),
+            vec![
+                Some(PortState::Open),
+                Some(PortState::Closed),
+                Some(PortState::Filtered)
+            ]
+        );
+        assert_eq!(
+            states(Protocol::Udp),
+            vec![
+                Some(PortState::Open),
+                Some(PortState::Closed),
+                Some(PortState::OpenFiltered)
+            ]
+        );
+    }
+
+    #[test]
+    fn retries_until_a_probe_answers() {
+        let network = SimulatedNetwork::new();
+        let socket = |port| SocketAddr::new("10.0.0.1".parse().unwrap(), port);
+        for protocol in [Protocol::Tcp, Protocol::Udp] {
+            network.script(
+                protocol,
+                socket(1),
+                [
+                    SocketBehavior::Drop,
+                    SocketBehavior::Drop,
+                    SocketBehavior::Open,
+                ],
+            );
+            network.script(protocol, socket(2), [SocketBehavior::Drop]);
+        }
+        network.script(Protocol::Udp, socket(3), [SocketBehavior::Refused]);
+
+        let scanner = serial_scanner(&[socket(1).ip()], vec![1, 2, 3], |builder| {
+            builder
+                .timeout(Duration::from_millis(50))
+                .tries(3)
+                .udp_ports(vec![1, 2, 3])
+                .transport(network.clone())
+        });
+        let report = block_on(scanner.run());
+
+        for protocol in [Protocol::Tcp, Protocol::Udp] {
+            assert_eq!(report.state(socket(1), protocol), Some(PortState::Open));
+            assert_eq!(network.probes(protocol, socket(1)), 3);
+            assert_eq!(network.probes(protocol, socket(2)), 3);
+        }
+        // An ICMP port unreachable ends the tries right away.
+        assert_eq!(
+            report.state(socket(3), Protocol::Udp),
+            Some(PortState::Closed)
+        );
+        assert_eq!(network.probes(Protocol::Udp, socket(3)), 1);
+    }
+
+    #[test]
+    fn answers_past_the_timeout_are_missed() {
+        let network = SimulatedNetwork::new();
+        let socket = |port| SocketAddr::new("10.0.0.1".parse().unwrap(), port);
+        for protocol in [Protocol::Tcp, Protocol::Udp] {
+            network.script(
+                protocol,
+                socket(1),
+                [SocketBehavior::delayed(
+                    Duration::from_millis(10),
+                    SocketBehavior::Open,
+                )],
+            );
+            network.script(
+                protocol,
+                socket(2),
+                [SocketBehavior::delayed(
+                    Duration::from_millis(200),
+                    SocketBehavior::Open,
+                )],
+            );
+        }
+
+        let started = Instant::now();
+        let scanner = serial_scanner(&[socket(1).ip()], vec![1, 2], |builder| {
+            builder
+                .timeout(Duration::from_millis(50))
+                .tries(1)
+                .udp_ports(vec![1, 2])
+                .transport(network.clone())
+        });
+        let report = block_on(scanner.run());
+
+        assert!(started.elapsed() < Duration::from_millis(200));
+        assert_eq!(
+            report.state(socket(1), Protocol::Tcp),
+            Some(PortState::Open)
+        );
+        assert_eq!(
+            report.state(socket(2), Protocol::Tcp),
+            Some(PortState::Filtered)
+        );
+        assert_eq!(
+            report.state(socket(1), Protocol::Udp),
+            Some(PortState::Open)
+        );
+        assert_eq!(
+            report.state(socket(2), Protocol::Udp),
+            Some(PortState::OpenFiltered)
+        );
+    }
 }
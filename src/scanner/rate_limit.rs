@@ -0,0 +1,205 @@
+//! Spaces out probes so a scan stays under a packet rate, both overall and
+//! towards any one host, for links and IDSes that punish bursts.
+use async_std::task;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Per-host buckets are only pruned once there are more than this many,
+/// finished hosts are cheap to keep around until then.
+const PRUNE_HOSTS_ABOVE: usize = 4096;
+
+/// A token bucket holding a single token, refilled at a fixed rate.
+///
+/// Not letting tokens pile up means the rate is never exceeded over any
+/// window of time, not even right after an idle period.
+#[derive(Debug)]
+struct TokenBucket {
+    interval: Duration,
+    next_token: Instant,
+}
+
+impl TokenBucket {
+    fn new(per_second: u32, now: Instant) -> Self {
+        Self {
+            interval: Duration::from_secs(1) / per_second,
+            next_token: now,
+        }
+    }
+}
+
+/// Caps the number of probes sent per second, over the whole scan and for
+/// each target host.
+///
+/// Every probe reserves the next free slot of the buckets it goes through
+/// and waits for it, so sockets scanned at the same time queue up instead
+/// of all firing at once. Clones share their buckets, so host discovery
+/// and the scan that follows it stay within the same rates.
+///
+/// ```rust
+/// # use async_std::task::block_on;
+/// # use rustscan::scanner::RateLimiter;
+/// // At most 1000 probes per second, and 10 per second to any single host.
+/// let limiter = RateLimiter::new(Some(1000), Some(10));
+/// block_on(limiter.acquire("127.0.0.1".parse().unwrap()));
+/// ```
+#[derive(Debug, Default, Clone)]
+pub struct RateLimiter {
+    global: Option<u32>,
+    per_host: Option<u32>,
+    buckets: Arc<Mutex<Buckets>>,
+}
+
+#[derive(Debug, Default)]
+struct Buckets {
+    global: Option<TokenBucket>,
+    per_host: HashMap<IpAddr, TokenBucket>,
+}
+
+impl RateLimiter {
+    /// Builds a limiter from probes per second allowed globally and per host.
+    /// `None` or zero leaves that side unlimited.
+    pub fn new(global: Option<u32>, per_host: Option<u32>) -> Self {
+        Self {
+            global: global.filter(|rate| *rate > 0),
+            per_host: per_host.filter(|rate| *rate > 0),
+            buckets: Arc::default(),
+        }
+    }
+
+    /// A limiter that never waits.
+    pub fn unlimited() -> Self {
+        Self::default()
+    }
+
+    pub fn is_unlimited(&self) -> bool {
+        self.global.is_none() && self.per_host.is_none()
+    }
+
+    /// Waits until a probe may be sent to `ip`.
+    pub async fn acquire(&self, ip: IpAddr) {
+        if self.is_unlimited() {
+            return;
+        }
+
+        let wait = self.reserve(ip, Instant::now());
+        if !wait.is_zero() {
+            task::sleep(wait).await;
+        }
+    }
+
+    /// Reserves the earliest slot allowed by both the global and the host
+    /// bucket, returning how long to wait for it.
+    ///
+    /// Each bucket moves on from its own next token. Pushing the global
+    /// bucket to a slot the host bucket is waiting for would make every
+    /// other host wait on the slowest one.
+    fn reserve(&self, ip: IpAddr, now: Instant) -> Duration {
+        let mut buckets = self.buckets.lock().unwrap();
+        let Buckets { global, per_host } = &mut *buckets;
+
+        let global_slot = self.global.map_or(now, |rate| {
+            let bucket = global.get_or_insert_with(|| TokenBucket::new(rate, now));
+            let slot = bucket.next_token.max(now);
+            bucket.next_token = slot + bucket.interval;
+            slot
+        });
+
+        if per_host.len() > PRUNE_HOSTS_ABOVE {
+            per_host.retain(|_, bucket| bucket.next_token > now);
+        }
+        let slot = self.per_host.map_or(global_slot, |rate| {
+            let bucket = per_host
+                .entry(ip)
+                .or_insert_with(|| TokenBucket::new(rate, now));
+            let slot = bucket.next_token.max(global_slot);
+            bucket.next_token = slot + bucket.interval;
+            slot
+        });
+
+        slot - now
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RateLimiter;
+    use std::net::IpAddr;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn unlimited_never_waits() {
+        let limiter = RateLimiter::unlimited();
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let now = Instant::now();
+
+        assert!(limiter.is_unlimited());
+        assert_eq!(limiter.reserve(ip, now), Duration::ZERO);
+        assert_eq!(limiter.reserve(ip, now), Duration::ZERO);
+    }
+
+    #[test]
+    fn global_rate_spaces_out_probes() {
+        let limiter = RateLimiter::new(Some(10), None);
+        let first = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let second = "127.0.0.2".parse::<IpAddr>().unwrap();
+        let now = Instant::now();
+
+        assert_eq!(limiter.reserve(first, now), Duration::ZERO);
+        assert_eq!(limiter.reserve(second, now), Duration::from_millis(100));
+        assert_eq!(limiter.reserve(first, now), Duration::from_millis(200));
+    }
+
+    #[test]
+    fn per_host_rate_only_spaces_out_the_same_host() {
+        let limiter = RateLimiter::new(None, Some(4));
+        let first = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let second = "127.0.0.2".parse::<IpAddr>().unwrap();
+        let now = Instant::now();
+
+        assert_eq!(limiter.reserve(first, now), Duration::ZERO);
+        assert_eq!(limiter.reserve(second, now), Duration::ZERO);
+        assert_eq!(limiter.reserve(first, now), Duration::from_millis(250));
+    }
+
+    #[test]
+    fn slowest_bucket_wins() {
+        let limiter = RateLimiter::new(Some(100), Some(2));
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let now = Instant::now();
+
+        assert_eq!(limiter.reserve(ip, now), Duration::ZERO);
+        assert_eq!(limiter.reserve(ip, now), Duration::from_millis(500));
+    }
+
+    #[test]
+    fn slow_host_does_not_hold_back_the_others() {
+        let limiter = RateLimiter::new(Some(1000), Some(1));
+        let first = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let second = "127.0.0.2".parse::<IpAddr>().unwrap();
+        let now = Instant::now();
+
+        assert_eq!(limiter.reserve(first, now), Duration::ZERO);
+        assert_eq!(limiter.reserve(first, now), Duration::from_secs(1));
+        assert_eq!(limiter.reserve(second, now), Duration::from_millis(2));
+        assert_eq!(limiter.reserve(second, now), Duration::from_millis(1_002));
+    }
+
+    #[test]
+    fn clones_share_their_buckets() {
+        let limiter = RateLimiter::new(Some(10), None);
+        let clone = limiter.clone();
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let now = Instant::now();
+
+        assert_eq!(limiter.reserve(ip, now), Duration::ZERO);
+        assert_eq!(clone.reserve(ip, now), Duration::from_millis(100));
+    }
+
+    #[test]
+    fn zero_means_unlimited() {
+        let limiter = RateLimiter::new(Some(0), Some(0));
+        assert!(limiter.is_unlimited());
+    }
+}
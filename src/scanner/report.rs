@@ -0,0 +1,457 @@
+//! Structured results of a scan, per IP and port.
+use super::banner::Banner;
+use super::service::ServiceMatch;
+use super::tls::TlsInfo;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+
+/// The transport protocol a port was scanned over.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Protocol::Tcp => write!(f, "tcp"),
+            Protocol::Udp => write!(f, "udp"),
+        }
+    }
+}
+
+/// Represents the state a socket was found in after probing it.
+///   - Open means the connection was accepted, or a UDP probe got an answer.
+///   - Closed means the host actively refused the connection.
+///   - Filtered means nothing answered before the timeout, which usually
+///     points to a firewall silently dropping the probe.
+///   - OpenFiltered means a UDP probe got no answer at all. Services often
+///     ignore probes they don't understand, so the port may as well be open.
+///   - Error holds the kind of any other I/O error seen on the last try.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PortState {
+    Open,
+    Closed,
+    Filtered,
+    OpenFiltered,
+    Error(io::ErrorKind),
+}
+
+impl PortState {
+    /// Classifies the error returned by a probe.
+    ///
+    /// ```rust
+    /// # use rustscan::scanner::PortState;
+    /// # use std::io;
+    /// let refused = io::Error::from(io::ErrorKind::ConnectionRefused);
+    /// assert_eq!(PortState::from_error(&refused), PortState::Closed);
+    /// ```
+    pub fn from_error(error: &io::Error) -> Self {
+        match error.kind() {
+            io::ErrorKind::ConnectionRefused | io::ErrorKind::ConnectionReset => PortState::Closed,
+            io::ErrorKind::TimedOut => PortState::Filtered,
+            kind => PortState::Error(kind),
+        }
+    }
+
+    /// Classifies the error returned by a UDP probe. A closed UDP port is
+    /// told by an ICMP port unreachable, which comes back as a refused
+    /// connection, while silence could be an open port or a firewall.
+    pub fn from_udp_error(error: &io::Error) -> Self {
+        match error.kind() {
+            io::ErrorKind::TimedOut => PortState::OpenFiltered,
+            _ => Self::from_error(error),
+        }
+    }
+}
+
+impl fmt::Display for PortState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PortState::Open => write!(f, "open"),
+            PortState::Closed => write!(f, "closed"),
+            PortState::Filtered => write!(f, "filtered"),
+            PortState::OpenFiltered => write!(f, "open|filtered"),
+            PortState::Error(kind) => write!(f, "error ({kind})"),
+        }
+    }
+}
+
+/// Something that happened during a scan, as yielded by `Scanner::stream`.
+///   - Open carries a socket that accepted the connection or answered, and
+///     the protocol it was scanned over.
+///   - Closed carries any socket that is not open, the state tells whether
+///     it was refused, filtered or failed.
+///   - Banner carries what the service on an open socket sent, right before
+///     the Open event of that socket.
+///   - HostFinished is sent once every port of an IP has been scanned.
+///   - HostIncomplete is sent at the end of the scan for every IP with ports
+///     left unscanned, because it ran out of time or the scan was cancelled.
+///   - HostsNotReached is sent instead for the IPs the scan stopped before
+///     getting to, with how many there are since a range may hold billions.
+///   - Progress tells how many sockets out of the total have been scanned.
+///   - OutOfFiles is sent at the end of the scan if the process ran out of
+///     open files, with the most sockets the scan went on with at a time.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScanEvent {
+    Open(SocketAddr, Protocol),
+    Closed(SocketAddr, Protocol, PortState),
+    Banner(SocketAddr, Banner),
+    HostFinished(IpAddr),
+    HostIncomplete(IpAddr),
+    HostsNotReached(u128),
+    Progress { scanned: usize, total: usize },
+    OutOfFiles(usize),
+}
+
+/// Number of ports found in each state for a single host.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct HostSummary {
+    pub open: usize,
+    pub closed: usize,
+    pub filtered: usize,
+    pub open_filtered: usize,
+    pub errors: usize,
+}
+
+impl HostSummary {
+    pub fn total(&self) -> usize {
+        self.open + self.closed + self.filtered + self.open_filtered + self.errors
+    }
+
+    fn count(&mut self, state: PortState) {
+        match state {
+            PortState::Open => self.open += 1,
+            PortState::Closed => self.closed += 1,
+            PortState::Filtered => self.filtered += 1,
+            PortState::OpenFiltered => self.open_filtered += 1,
+            PortState::Error(_) => self.errors += 1,
+        }
+    }
+}
+
+impl fmt::Display for HostSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} open, {} closed, {} filtered",
+            self.open, self.closed, self.filtered
+        )?;
+        // Only UDP scans find ports open|filtered.
+        if self.open_filtered > 0 {
+            write!(f, ", {} open|filtered", self.open_filtered)?;
+        }
+        write!(f, ", {} errors", self.errors)
+    }
+}
+
+/// The outcome of a scan: the state of every probed port, grouped by IP.
+///
+/// A report built with `summary_only` keeps open ports alone and only
+/// counts the others. A scan of many hosts and ports finds most of them
+/// closed or filtered, and keeping each one would grow with hosts times
+/// ports.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ScanReport {
+    hosts: BTreeMap<IpAddr, HostPorts>,
+    summary_only: bool,
+    banners: BTreeMap<SocketAddr, Banner>,
+    services: BTreeMap<SocketAddr, ServiceMatch>,
+    tls: BTreeMap<SocketAddr, TlsInfo>,
+    incomplete: BTreeSet<IpAddr>,
+    not_reached: u128,
+    out_of_files: Option<usize>,
+}
+
+/// The state of each port of a single host, and the number of ports that
+/// were only counted.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+struct HostPorts {
+    states: BTreeMap<(Protocol, u16), PortState>,
+    counted: HostSummary,
+}
+
+impl ScanReport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A report keeping the state of open ports only, every other port is
+    /// just counted in the summary of its host.
+    pub fn summary_only() -> Self {
+        Self {
+            summary_only: true,
+            ..Self::default()
+        }
+    }
+
+    /// Records the state of a socket, replacing any previous state.
+    pub fn insert(&mut self, socket: SocketAddr, protocol: Protocol, state: PortState) {
+        let host = self.hosts.entry(socket.ip()).or_default();
+        if self.summary_only && state != PortState::Open {
+            host.states.remove(&(protocol, socket.port()));
+            host.counted.count(state);
+        } else {
+            host.states.insert((protocol, socket.port()), state);
+        }
+    }
+
+    /// Records the socket carried by a scan event, other events are ignored.
+    pub fn record(&mut self, event: &ScanEvent) {
+        match event {
+            ScanEvent::Open(socket, protocol) => self.insert(*socket, *protocol, PortState::Open),
+            ScanEvent::Closed(socket, protocol, state) => self.insert(*socket, *protocol, *state),
+            ScanEvent::Banner(socket, banner) => {
+                self.banners.insert(*socket, banner.clone());
+            }
+            ScanEvent::HostIncomplete(ip) => {
+                self.incomplete.insert(*ip);
+            }
+            ScanEvent::HostsNotReached(hosts) => self.not_reached += hosts,
+            ScanEvent::OutOfFiles(max_sockets) => self.out_of_files = Some(*max_sockets),
+            ScanEvent::HostFinished(_) | ScanEvent::Progress { .. } => {}
+        }
+    }
+
+    /// Returns the state recorded for a socket, if it was probed over
+    /// `protocol`. Only open sockets have one in a `summary_only` report.
+    pub fn state(&self, socket: SocketAddr, protocol: Protocol) -> Option<PortState> {
+        self.hosts
+            .get(&socket.ip())
+            .and_then(|host| host.states.get(&(protocol, socket.port())))
+            .copied()
+    }
+
+    /// Returns the banner grabbed from a socket, if any.
+    pub fn banner(&self, socket: SocketAddr) -> Option<&Banner> {
+        self.banners.get(&socket)
+    }
+
+    /// Records the service identified behind a socket.
+    pub fn insert_service(&mut self, socket: SocketAddr, service: ServiceMatch) {
+        self.services.insert(socket, service);
+    }
+
+    /// Returns the service identified behind a socket, if any.
+    pub fn service(&self, socket: SocketAddr) -> Option<&ServiceMatch> {
+        self.services.get(&socket)
+    }
+
+    /// Iterates over every socket whose service was identified.
+    pub fn services(&self) -> impl Iterator<Item = (&SocketAddr, &ServiceMatch)> {
+        self.services.iter()
+    }
+
+    /// Records the outcome of a successful TLS handshake with a socket.
+    pub fn insert_tls(&mut self, socket: SocketAddr, info: TlsInfo) {
+        self.tls.insert(socket, info);
+    }
+
+    /// Returns what the TLS handshake with a socket told, if it succeeded.
+    pub fn tls(&self, socket: SocketAddr) -> Option<&TlsInfo> {
+        self.tls.get(&socket)
+    }
+
+    /// Iterates over every socket a TLS handshake succeeded with.
+    pub fn tls_sockets(&self) -> impl Iterator<Item = (&SocketAddr, &TlsInfo)> {
+        self.tls.iter()
+    }
+
+    /// Iterates over every probed host and the state of each of its ports,
+    /// ordered by protocol then port.
+    pub fn hosts(&self) -> impl Iterator<Item = (&IpAddr, &BTreeMap<(Protocol, u16), PortState>)> {
+        self.hosts.iter().map(|(ip, host)| (ip, &host.states))
+    }
+
+    /// Returns every socket found open over `protocol`, ordered by IP and port.
+    pub fn open_sockets(&self, protocol: Protocol) -> Vec<SocketAddr> {
+        self.hosts
+            .keys()
+            .flat_map(|ip| {
+                self.ports_with_state(*ip, protocol, PortState::Open)
+                    .into_iter()
+                    .map(move |port| SocketAddr::new(*ip, port))
+            })
+            .collect()
+    }
+
+    /// Returns the ports of `ip` scanned over `protocol` that were found in `state`.
+    pub fn ports_with_state(&self, ip: IpAddr, protocol: Protocol, state: PortState) -> Vec<u16> {
+        self.hosts
+            .get(&ip)
+            .map(|host| {
+                host.states
+                    .iter()
+                    .filter(|((p, _), s)| *p == protocol && **s == state)
+                    .map(|((_, port), _)| *port)
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Counts the ports of `ip` per state.
+    pub fn summary(&self, ip: IpAddr) -> HostSummary {
+        let Some(host) = self.hosts.get(&ip) else {
+            return HostSummary::default();
+        };
+        let mut summary = host.counted;
+        for state in host.states.values() {
+            summary.count(*state);
+        }
+        summary
+    }
+
+    /// Whether some ports of `ip` were left unscanned.
+    pub fn is_incomplete(&self, ip: IpAddr) -> bool {
+        self.incomplete.contains(&ip)
+    }
+
+    /// Iterates over every host with ports left unscanned, out of the ones
+    /// the scan got to.
+    pub fn incomplete_hosts(&self) -> impl Iterator<Item = &IpAddr> {
+        self.incomplete.iter()
+    }
+
+    /// Number of hosts the scan stopped before getting to.
+    pub fn hosts_not_reached(&self) -> u128 {
+        self.not_reached
+    }
+
+    /// The most sockets the scan went on with at a time, if it ran out of
+    /// open files.
+    pub fn out_of_files(&self) -> Option<usize> {
+        self.out_of_files
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hosts.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PortState, Protocol, ScanEvent, ScanReport};
+    use std::io;
+    use std::net::{IpAddr, SocketAddr};
+
+    #[test]
+    fn classifies_io_errors() {
+        let refused = io::Error::from(io::ErrorKind::ConnectionRefused);
+        let timed_out = io::Error::from(io::ErrorKind::TimedOut);
+        let unreachable = io::Error::from(io::ErrorKind::HostUnreachable);
+
+        assert_eq!(PortState::from_error(&refused), PortState::Closed);
+        assert_eq!(PortState::from_error(&timed_out), PortState::Filtered);
+        assert_eq!(PortState::from_udp_error(&refused), PortState::Closed);
+        assert_eq!(
+            PortState::from_udp_error(&timed_out),
+            PortState::OpenFiltered
+        );
+        assert_eq!(
+            PortState::from_error(&unreachable),
+            PortState::Error(io::ErrorKind::HostUnreachable)
+        );
+    }
+
+    #[test]
+    fn report_groups_states_per_host() {
+        let first = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let second = "192.168.0.1".parse::<IpAddr>().unwrap();
+        let mut report = ScanReport::new();
+
+        report.insert(SocketAddr::new(first, 443), Protocol::Tcp, PortState::Open);
+        report.insert(SocketAddr::new(first, 22), Protocol::Tcp, PortState::Open);
+        report.insert(SocketAddr::new(first, 80), Protocol::Tcp, PortState::Closed);
+        report.insert(
+            SocketAddr::new(second, 22),
+            Protocol::Tcp,
+            PortState::Filtered,
+        );
+
+        assert_eq!(
+            report.open_sockets(Protocol::Tcp),
+            vec![SocketAddr::new(first, 22), SocketAddr::new(first, 443)]
+        );
+        assert_eq!(
+            report.ports_with_state(second, Protocol::Tcp, PortState::Filtered),
+            vec![22]
+        );
+        assert_eq!(report.summary(first).open, 2);
+        assert_eq!(report.summary(first).closed, 1);
+        assert_eq!(report.summary(second).total(), 1);
+        assert_eq!(
+            report.state(SocketAddr::new(first, 80), Protocol::Tcp),
+            Some(PortState::Closed)
+        );
+    }
+
+    #[test]
+    fn summary_only_report_counts_all_but_open_ports() {
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let mut report = ScanReport::summary_only();
+
+        report.insert(SocketAddr::new(ip, 22), Protocol::Tcp, PortState::Open);
+        for port in 1_001..=2_000 {
+            report.insert(SocketAddr::new(ip, port), Protocol::Tcp, PortState::Closed);
+        }
+        for port in 1..=500 {
+            report.insert(
+                SocketAddr::new(ip, port),
+                Protocol::Udp,
+                PortState::OpenFiltered,
+            );
+        }
+        report.insert(SocketAddr::new(ip, 80), Protocol::Udp, PortState::Filtered);
+
+        let summary = report.summary(ip);
+        assert_eq!(summary.closed, 1000);
+        assert_eq!(summary.open_filtered, 500);
+        assert_eq!(summary.filtered, 1);
+        assert_eq!(summary.open, 1);
+        assert_eq!(
+            report.state(SocketAddr::new(ip, 1_001), Protocol::Tcp),
+            None
+        );
+        assert_eq!(report.state(SocketAddr::new(ip, 80), Protocol::Udp), None);
+        assert_eq!(
+            report.open_sockets(Protocol::Tcp),
+            vec![SocketAddr::new(ip, 22)]
+        );
+        assert_eq!(
+            report.hosts().map(|(_, ports)| ports.len()).sum::<usize>(),
+            1
+        );
+        assert!(!report.is_empty());
+    }
+
+    #[test]
+    fn report_tells_protocols_apart() {
+        let socket = "127.0.0.1:53".parse::<SocketAddr>().unwrap();
+        let mut report = ScanReport::new();
+
+        report.record(&ScanEvent::Closed(socket, Protocol::Tcp, PortState::Closed));
+        report.record(&ScanEvent::Open(socket, Protocol::Udp));
+
+        assert!(report.open_sockets(Protocol::Tcp).is_empty());
+        assert_eq!(report.open_sockets(Protocol::Udp), vec![socket]);
+        assert_eq!(report.state(socket, Protocol::Tcp), Some(PortState::Closed));
+        assert_eq!(report.summary(socket.ip()).total(), 2);
+    }
+
+    #[test]
+    fn report_keeps_incomplete_hosts() {
+        let first = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let second = "192.168.0.1".parse::<IpAddr>().unwrap();
+        let mut report = ScanReport::new();
+
+        report.record(&ScanEvent::HostFinished(first));
+        report.record(&ScanEvent::HostIncomplete(second));
+
+        assert!(!report.is_incomplete(first));
+        assert!(report.is_incomplete(second));
+        assert_eq!(report.incomplete_hosts().collect::<Vec<_>>(), vec![&second]);
+    }
+}
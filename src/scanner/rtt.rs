@@ -0,0 +1,148 @@
+//! Derives the probe timeout of each host from how fast it has answered so
+//! far, so a nearby host is not waited on as long as a distant one.
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Bounds of the probe timeout derived from the round trip times of a host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RttTimeouts {
+    pub min: Duration,
+    pub max: Duration,
+}
+
+/// Smoothed round trip time and its variance, as computed for TCP
+/// retransmissions in RFC 6298.
+#[derive(Debug, Clone, Copy)]
+struct HostRtt {
+    srtt: Duration,
+    rttvar: Duration,
+}
+
+impl HostRtt {
+    fn new(sample: Duration) -> Self {
+        Self {
+            srtt: sample,
+            rttvar: sample / 2,
+        }
+    }
+
+    fn update(&mut self, sample: Duration) {
+        let deviation = self.srtt.abs_diff(sample);
+        self.rttvar = self.rttvar * 3 / 4 + deviation / 4;
+        self.srtt = self.srtt * 7 / 8 + sample / 8;
+    }
+
+    fn timeout(&self) -> Duration {
+        self.srtt + self.rttvar * 4
+    }
+}
+
+/// Tracks the round trip time of every scanned host to give each one a
+/// probe timeout fitting how fast it answers.
+///
+/// Hosts that did not answer anything yet get the initial timeout, every
+/// timeout stays within the bounds.
+#[derive(Debug)]
+pub struct RttEstimator {
+    initial: Duration,
+    bounds: RttTimeouts,
+    hosts: Mutex<HashMap<IpAddr, HostRtt>>,
+}
+
+impl RttEstimator {
+    pub fn new(initial: Duration, bounds: RttTimeouts) -> Self {
+        let bounds = RttTimeouts {
+            min: bounds.min,
+            max: bounds.max.max(bounds.min),
+        };
+        Self {
+            initial: initial.clamp(bounds.min, bounds.max),
+            bounds,
+            hosts: Mutex::default(),
+        }
+    }
+
+    /// Feeds the time a host took to accept or refuse a connection.
+    pub fn record(&self, ip: IpAddr, sample: Duration) {
+        self.hosts
+            .lock()
+            .unwrap()
+            .entry(ip)
+            .and_modify(|rtt| rtt.update(sample))
+            .or_insert_with(|| HostRtt::new(sample));
+    }
+
+    /// How long to wait for an answer from `ip`.
+    pub fn timeout(&self, ip: IpAddr) -> Duration {
+        self.hosts
+            .lock()
+            .unwrap()
+            .get(&ip)
+            .map_or(self.initial, |rtt| {
+                rtt.timeout().clamp(self.bounds.min, self.bounds.max)
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RttEstimator, RttTimeouts};
+    use std::net::IpAddr;
+    use std::time::Duration;
+
+    const BOUNDS: RttTimeouts = RttTimeouts {
+        min: Duration::from_millis(100),
+        max: Duration::from_millis(5_000),
+    };
+
+    #[test]
+    fn unknown_host_gets_initial_timeout() {
+        let estimator = RttEstimator::new(Duration::from_millis(1_500), BOUNDS);
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+        assert_eq!(estimator.timeout(ip), Duration::from_millis(1_500));
+    }
+
+    #[test]
+    fn initial_timeout_is_bounded() {
+        let estimator = RttEstimator::new(Duration::from_millis(10_000), BOUNDS);
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+        assert_eq!(estimator.timeout(ip), Duration::from_millis(5_000));
+    }
+
+    #[test]
+    fn timeout_follows_samples() {
+        let estimator = RttEstimator::new(Duration::from_millis(1_500), BOUNDS);
+        let ip = "10.0.0.1".parse::<IpAddr>().unwrap();
+
+        // First sample: srtt = 200, rttvar = 100.
+        estimator.record(ip, Duration::from_millis(200));
+        assert_eq!(estimator.timeout(ip), Duration::from_millis(600));
+
+        // rttvar = 75 + 0, srtt = 200.
+        estimator.record(ip, Duration::from_millis(200));
+        assert_eq!(estimator.timeout(ip), Duration::from_millis(500));
+    }
+
+    #[test]
+    fn hosts_are_tracked_separately() {
+        let estimator = RttEstimator::new(Duration::from_millis(1_500), BOUNDS);
+        let near = "192.168.0.1".parse::<IpAddr>().unwrap();
+        let far = "203.0.113.1".parse::<IpAddr>().unwrap();
+
+        estimator.record(near, Duration::from_millis(1));
+        estimator.record(far, Duration::from_millis(900));
+
+        assert_eq!(estimator.timeout(near), BOUNDS.min);
+        assert_eq!(estimator.timeout(far), Duration::from_millis(2_700));
+    }
+
+    #[test]
+    fn slow_host_is_capped_by_max() {
+        let estimator = RttEstimator::new(Duration::from_millis(1_500), BOUNDS);
+        let ip = "203.0.113.1".parse::<IpAddr>().unwrap();
+        estimator.record(ip, Duration::from_millis(4_000));
+        assert_eq!(estimator.timeout(ip), BOUNDS.max);
+    }
+}
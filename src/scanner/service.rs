@@ -0,0 +1,353 @@
+//! Native service and version detection, driven by the probe database
+//! compiled from `nmap-service-probes` by build.rs.
+use super::{Protocol, ScanReport, Scanner};
+use crate::generated::{get_service_probes, ServiceMatchData, ServiceProbeData};
+use async_std::io;
+use async_std::prelude::*;
+use futures::stream::FuturesUnordered;
+use log::{debug, warn};
+use once_cell::sync::Lazy;
+use regex::bytes::{Captures, Regex, RegexBuilder};
+use std::fmt;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+/// Probes rarer than this are only sent to the ports they list, like nmap's
+/// default version intensity.
+const MAX_RARITY: u8 = 7;
+
+const RESPONSE_BUFFER_SIZE: usize = 4096;
+
+/// Once a service started answering, how long to wait for the rest of it.
+const READ_GRACE: Duration = Duration::from_millis(250);
+
+/// The service identified behind an open port.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServiceMatch {
+    pub service: String,
+    pub product: Option<String>,
+    pub version: Option<String>,
+    pub info: Option<String>,
+    /// Whether only the service is known, because a softmatch rule matched.
+    pub soft: bool,
+}
+
+impl fmt::Display for ServiceMatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.service)?;
+        if let Some(product) = &self.product {
+            write!(f, " {product}")?;
+        }
+        if let Some(version) = &self.version {
+            write!(f, " {version}")?;
+        }
+        if let Some(info) = &self.info {
+            write!(f, " ({info})")?;
+        }
+        Ok(())
+    }
+}
+
+struct CompiledMatch {
+    data: &'static ServiceMatchData,
+    regex: Regex,
+}
+
+/// The match rules of every probe, compiled once on first use. Rules the
+/// regex crate does not understand are skipped.
+static COMPILED_MATCHES: Lazy<Vec<Vec<CompiledMatch>>> = Lazy::new(|| {
+    get_service_probes()
+        .iter()
+        .map(|probe| {
+            probe
+                .matches
+                .iter()
+                .filter_map(
+                    |data| match RegexBuilder::new(data.pattern).unicode(false).build() {
+                        Ok(regex) => Some(CompiledMatch { data, regex }),
+                        Err(e) => {
+                            warn!(
+                                "Skipping {} rule of probe {}: {}",
+                                data.service, probe.name, e
+                            );
+                            None
+                        }
+                    },
+                )
+                .collect()
+        })
+        .collect()
+});
+
+impl CompiledMatch {
+    fn apply(&self, response: &[u8]) -> Option<ServiceMatch> {
+        let captures = self.regex.captures(response)?;
+        Some(ServiceMatch {
+            service: self.data.service.to_owned(),
+            product: self.data.product.and_then(|t| substitute(t, &captures)),
+            version: self.data.version.and_then(|t| substitute(t, &captures)),
+            info: self.data.info.and_then(|t| substitute(t, &captures)),
+            soft: self.data.soft,
+        })
+    }
+}
+
+/// Replaces `$1` to `$9` in a version field by the matching capture group.
+/// Returns None when nothing is left once substituted.
+fn substitute(template: &str, captures: &Captures) -> Option<String> {
+    let mut result = String::new();
+    let mut chars = template.chars().peekable();
+
+    while let Some(char) = chars.next() {
+        let group = chars.peek().and_then(|next| next.to_digit(10));
+        match group {
+            Some(group) if char == '$' => {
+                chars.next();
+                if let Some(capture) = captures.get(group as usize) {
+                    result.push_str(&String::from_utf8_lossy(capture.as_bytes()));
+                }
+            }
+            _ => result.push(char),
+        }
+    }
+
+    let result = result.trim();
+    (!result.is_empty()).then(|| result.to_owned())
+}
+
+/// Matches the response to the probe at `probe_index` against that probe's
+/// rules, then against the NULL probe's ones. A hard match anywhere wins over
+/// the first softmatch.
+fn match_response(probe_index: usize, response: &[u8]) -> Option<ServiceMatch> {
+    let probes = get_service_probes();
+    let mut rules: Vec<&CompiledMatch> = COMPILED_MATCHES[probe_index].iter().collect();
+    if let Some(null_index) = probes.iter().position(|probe| probe.name == "NULL") {
+        if null_index != probe_index {
+            rules.extend(&COMPILED_MATCHES[null_index]);
+        }
+    }
+
+    let mut soft_match = None;
+    for rule in rules {
+        if rule.data.soft && soft_match.is_some() {
+            continue;
+        }
+        match rule.apply(response) {
+            Some(service) if !service.soft => return Some(service),
+            Some(service) => soft_match = Some(service),
+            None => {}
+        }
+    }
+    soft_match
+}
+
+/// The probes worth sending to `port`, in the order they should be sent:
+/// the NULL probe, the probes listing the port, then the common ones.
+fn probes_for(port: u16) -> Vec<usize> {
+    let probes: &[ServiceProbeData] = get_service_probes();
+    let mut ordered: Vec<usize> = Vec::new();
+
+    ordered.extend(probes.iter().position(|probe| probe.name == "NULL"));
+    ordered.extend(
+        probes
+            .iter()
+            .enumerate()
+            .filter(|(_, probe)| probe.name != "NULL" && probe.ports.contains(&port))
+            .map(|(index, _)| index),
+    );
+    ordered.extend(
+        probes
+            .iter()
+            .enumerate()
+            .filter(|(_, probe)| {
+                probe.name != "NULL" && !probe.ports.contains(&port) && probe.rarity <= MAX_RARITY
+            })
+            .map(|(index, _)| index),
+    );
+
+    ordered
+}
+
+impl Scanner {
+    /// Identifies the service behind every open TCP socket of `report`,
+    /// recording what it found back into the report.
+    ///
+    /// Each probe is sent over a fresh connection, going through the same
+    /// timeout, rate limit and batch size as the scan itself. UDP sockets
+    /// are left untouched since every probe of the database is a TCP one.
+    /// No new socket is probed once the scanner is cancelled.
+    pub async fn detect_services(&self, report: &mut ScanReport) {
+        let mut sockets = report.open_sockets(Protocol::Tcp).into_iter();
+        let mut ftrs = FuturesUnordered::new();
+        for socket in sockets.by_ref().take(self.batch_size.max(1).into()) {
+            ftrs.push(self.detect_service(socket));
+        }
+
+        while let Some((socket, service)) = ftrs.next().await {
+            if let Some(socket) = sockets.next().filter(|_| !self.cancellation.is_cancelled()) {
+                ftrs.push(self.detect_service(socket));
+            }
+
+            if let Some(service) = service {
+                debug!("Service found on {}: {}", socket, service);
+                report.insert_service(socket, service);
+            }
+        }
+    }
+
+    async fn detect_service(&self, socket: SocketAddr) -> (SocketAddr, Option<ServiceMatch>) {
+        let probes = get_service_probes();
+        let mut soft_match = None;
+
+        for probe_index in probes_for(socket.port()) {
+            let Some(response) = self.send_probe(socket, &probes[probe_index]).await else {
+                continue;
+            };
+
+            match match_response(probe_index, &response) {
+                Some(service) if !service.soft => return (socket, Some(service)),
+                Some(service) => {
+                    soft_match.get_or_insert(service);
+                }
+                None => {}
+            }
+        }
+
+        (socket, soft_match)
+    }
+
+    /// Sends a probe over a new connection and returns what came back, if anything.
+    async fn send_probe(&self, socket: SocketAddr, probe: &ServiceProbeData) -> Option<Vec<u8>> {
+        let mut stream = self.connect(socket).await.ok()?;
+        if !probe.payload.is_empty() {
+            stream.write_all(probe.payload).await.ok()?;
+        }
+
+        let mut response = vec![0u8; RESPONSE_BUFFER_SIZE];
+        let mut size = 0;
+        let mut wait = self.timeout;
+        while size < response.len() {
+            match io::timeout(wait, stream.read(&mut response[size..])).await {
+                Ok(0) | Err(_) => break,
+                Ok(read) => size += read,
+            }
+            wait = READ_GRACE.min(self.timeout);
+        }
+
+        response.truncate(size);
+        (!response.is_empty()).then_some(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{match_response, probes_for, ServiceMatch, COMPILED_MATCHES};
+    use crate::generated::get_service_probes;
+    use crate::input::ScanOrder;
+    use crate::port_strategy::PortStrategy;
+    use crate::scanner::Scanner;
+    use async_std::task::block_on;
+    use std::io::Write;
+    use std::net::{IpAddr, SocketAddr, TcpListener};
+    use std::thread;
+    use std::time::Duration;
+
+    fn probe_index(name: &str) -> usize {
+        get_service_probes()
+            .iter()
+            .position(|probe| probe.name == name)
+            .unwrap()
+    }
+
+    #[test]
+    fn every_rule_compiles() {
+        let rules: usize = get_service_probes()
+            .iter()
+            .map(|probe| probe.matches.len())
+            .sum();
+        let compiled: usize = COMPILED_MATCHES.iter().map(Vec::len).sum();
+        assert_eq!(rules, compiled);
+    }
+
+    #[test]
+    fn matches_openssh_banner() {
+        let service = match_response(probe_index("NULL"), b"SSH-2.0-OpenSSH_9.6p1 Ubuntu-3\r\n");
+        assert_eq!(
+            service,
+            Some(ServiceMatch {
+                service: "ssh".to_owned(),
+                product: Some("OpenSSH".to_owned()),
+                version: Some("9.6p1".to_owned()),
+                info: Some("protocol 2.0".to_owned()),
+                soft: false,
+            })
+        );
+    }
+
+    #[test]
+    fn matches_http_server_header() {
+        let response = b"HTTP/1.1 200 OK\r\nDate: Mon, 01 Jan 2024 00:00:00 GMT\r\nServer: Apache/2.4.58 (Debian)\r\n\r\n";
+        let service = match_response(probe_index("GetRequest"), response).unwrap();
+        assert_eq!(service.to_string(), "http Apache httpd 2.4.58 (Debian)");
+    }
+
+    #[test]
+    fn falls_back_to_null_rules_and_soft_matches() {
+        // An SSH server answers a GET request with its banner anyway.
+        let service = match_response(probe_index("GetRequest"), b"SSH-2.0-dropbear_2022.83\r\n");
+        assert_eq!(service.unwrap().product.as_deref(), Some("Dropbear sshd"));
+
+        let service = match_response(probe_index("GetRequest"), b"HTTP/1.0 404 Not Found\r\n\r\n");
+        assert_eq!(
+            service,
+            Some(ServiceMatch {
+                service: "http".to_owned(),
+                product: None,
+                version: None,
+                info: None,
+                soft: true,
+            })
+        );
+    }
+
+    #[test]
+    fn null_probe_comes_first() {
+        let null = probe_index("NULL");
+        let get_request = probe_index("GetRequest");
+        assert_eq!(probes_for(8080)[..2], [null, get_request]);
+        assert_eq!(probes_for(22)[0], null);
+        assert_eq!(probes_for(6379)[1], probe_index("RedisPing"));
+    }
+
+    #[test]
+    fn detects_service_of_open_socket() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.write_all(b"220 (vsFTPd 3.0.5)\r\n").unwrap();
+        });
+
+        let addrs = vec!["127.0.0.1".parse::<IpAddr>().unwrap()];
+        let strategy = PortStrategy::pick(&None, Some(vec![port]), ScanOrder::Serial);
+        let scanner = Scanner::builder(&addrs, strategy)
+            .batch_size(10)
+            .timeout(Duration::from_millis(500))
+            .build()
+            .unwrap();
+        let socket = SocketAddr::new(addrs[0], port);
+        let mut report = crate::scanner::ScanReport::new();
+        report.insert(
+            socket,
+            crate::scanner::Protocol::Tcp,
+            crate::scanner::PortState::Open,
+        );
+
+        block_on(scanner.detect_services(&mut report));
+
+        assert_eq!(
+            report.service(socket).map(ToString::to_string),
+            Some("ftp vsftpd 3.0.5".to_owned())
+        );
+    }
+}
@@ -0,0 +1,229 @@
+//! A network held in memory, where every socket does what it was told to.
+//! Scans run against it without sending a single packet, which keeps tests
+//! of the scan logic fast and deterministic.
+use super::transport::{Connection, Transport};
+use super::Protocol;
+use async_std::io;
+use async_std::task;
+use futures::future::{self, BoxFuture};
+use futures::FutureExt;
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// What a socket of a `SimulatedNetwork` does with a probe.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SocketBehavior {
+    /// Accepts the connection, or answers the datagram.
+    Open,
+    /// Refuses the connection, or answers with an ICMP port unreachable.
+    Refused,
+    /// Never answers.
+    Drop,
+    /// Waits before doing what the inner behavior says.
+    Delayed(Duration, Box<SocketBehavior>),
+}
+
+impl SocketBehavior {
+    pub fn delayed(delay: Duration, behavior: SocketBehavior) -> Self {
+        SocketBehavior::Delayed(delay, Box::new(behavior))
+    }
+
+    /// How long to wait in all, and what to do then.
+    fn settle(self) -> (Duration, SocketBehavior) {
+        match self {
+            SocketBehavior::Delayed(delay, behavior) => {
+                let (inner, behavior) = behavior.settle();
+                (delay + inner, behavior)
+            }
+            behavior => (Duration::ZERO, behavior),
+        }
+    }
+}
+
+/// The behaviors left for a socket and how many probes it got.
+#[derive(Debug, Default)]
+struct Script {
+    behaviors: VecDeque<SocketBehavior>,
+    probes: usize,
+}
+
+impl Script {
+    /// The last behavior is kept for every probe after it, a socket that
+    /// was never scripted refuses them all.
+    fn next(&mut self) -> SocketBehavior {
+        self.probes += 1;
+        if self.behaviors.len() > 1 {
+            self.behaviors.pop_front().unwrap()
+        } else {
+            self.behaviors
+                .front()
+                .cloned()
+                .unwrap_or(SocketBehavior::Refused)
+        }
+    }
+}
+
+/// A `Transport` whose sockets follow a script instead of reaching the
+/// network. Clones share their sockets, a clone can be kept to check the
+/// probes once the scanner took the other one.
+///
+/// ```rust
+/// # use async_std::task::block_on;
+/// # use std::net::SocketAddr;
+/// # use rustscan::input::ScanOrder;
+/// # use rustscan::port_strategy::PortStrategy;
+/// # use rustscan::scanner::{PortState, Protocol, Scanner, SimulatedNetwork, SocketBehavior};
+/// let open: SocketAddr = "10.0.0.1:80".parse().unwrap();
+/// let network = SimulatedNetwork::new();
+/// network.script(Protocol::Tcp, open, [SocketBehavior::Open]);
+///
+/// let strategy = PortStrategy::pick(&None, Some(vec![80, 443]), ScanOrder::Serial);
+/// let scanner = Scanner::builder(&[open.ip()], strategy)
+///     .transport(network.clone())
+///     .build()
+///     .unwrap();
+/// let report = block_on(scanner.run());
+///
+/// assert_eq!(report.open_sockets(Protocol::Tcp), vec![open]);
+/// assert_eq!(network.probes(Protocol::Tcp, open), 1);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct SimulatedNetwork {
+    sockets: Arc<Mutex<HashMap<(Protocol, SocketAddr), Script>>>,
+}
+
+impl SimulatedNetwork {
+    /// A network where every socket refuses probes.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Makes `socket` answer its next probes over `protocol` with
+    /// `behaviors`, one per probe, the last one for every probe after.
+    pub fn script(
+        &self,
+        protocol: Protocol,
+        socket: SocketAddr,
+        behaviors: impl IntoIterator<Item = SocketBehavior>,
+    ) {
+        self.sockets
+            .lock()
+            .unwrap()
+            .entry((protocol, socket))
+            .or_default()
+            .behaviors = behaviors.into_iter().collect();
+    }
+
+    /// How many probes `socket` got over `protocol`.
+    pub fn probes(&self, protocol: Protocol, socket: SocketAddr) -> usize {
+        self.sockets
+            .lock()
+            .unwrap()
+            .get(&(protocol, socket))
+            .map_or(0, |script| script.probes)
+    }
+
+    fn next(&self, protocol: Protocol, socket: SocketAddr) -> SocketBehavior {
+        self.sockets
+            .lock()
+            .unwrap()
+            .entry((protocol, socket))
+            .or_default()
+            .next()
+    }
+}
+
+impl Transport for SimulatedNetwork {
+    fn connect(&self, target: SocketAddr) -> BoxFuture<'_, io::Result<Box<dyn Connection>>> {
+        let (delay, behavior) = self.next(Protocol::Tcp, target).settle();
+        async move {
+            task::sleep(delay).await;
+            match behavior {
+                // Reads end right away and writes go nowhere.
+                SocketBehavior::Open => {
+                    Ok(Box::new(futures::io::Cursor::new(Vec::new())) as Box<dyn Connection>)
+                }
+                SocketBehavior::Refused => Err(io::ErrorKind::ConnectionRefused.into()),
+                SocketBehavior::Drop | SocketBehavior::Delayed(..) => future::pending().await,
+            }
+        }
+        .boxed()
+    }
+
+    fn probe_udp<'a>(
+        &'a self,
+        target: SocketAddr,
+        _payloads: &'a [&'a [u8]],
+        wait: Duration,
+    ) -> BoxFuture<'a, io::Result<bool>> {
+        let (delay, behavior) = self.next(Protocol::Udp, target).settle();
+        async move {
+            if behavior == SocketBehavior::Drop || delay >= wait {
+                task::sleep(wait).await;
+                return Ok(false);
+            }
+            task::sleep(delay).await;
+            match behavior {
+                SocketBehavior::Refused => Err(io::ErrorKind::ConnectionRefused.into()),
+                _ => Ok(true),
+            }
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SimulatedNetwork, SocketBehavior};
+    use crate::scanner::transport::Transport;
+    use crate::scanner::Protocol;
+    use async_std::io;
+    use async_std::task::block_on;
+    use std::net::SocketAddr;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn follows_the_script_then_repeats_its_end() {
+        let network = SimulatedNetwork::new();
+        let socket: SocketAddr = "10.0.0.1:80".parse().unwrap();
+        network.script(
+            Protocol::Tcp,
+            socket,
+            [SocketBehavior::Refused, SocketBehavior::Open],
+        );
+
+        let kind = |result: io::Result<_>| result.err().map(|e| e.kind());
+        assert_eq!(
+            kind(block_on(network.connect(socket))),
+            Some(io::ErrorKind::ConnectionRefused)
+        );
+        assert_eq!(kind(block_on(network.connect(socket))), None);
+        assert_eq!(kind(block_on(network.connect(socket))), None);
+        assert_eq!(network.probes(Protocol::Tcp, socket), 3);
+        assert_eq!(network.probes(Protocol::Udp, socket), 0);
+    }
+
+    #[test]
+    fn udp_answers_only_within_the_wait() {
+        let network = SimulatedNetwork::new();
+        let socket: SocketAddr = "10.0.0.1:53".parse().unwrap();
+        let wait = Duration::from_millis(50);
+        network.script(
+            Protocol::Udp,
+            socket,
+            [
+                SocketBehavior::delayed(Duration::from_millis(10), SocketBehavior::Open),
+                SocketBehavior::delayed(Duration::from_millis(100), SocketBehavior::Open),
+                SocketBehavior::Drop,
+            ],
+        );
+
+        assert!(block_on(network.probe_udp(socket, &[], wait)).unwrap());
+        let started = Instant::now();
+        assert!(!block_on(network.probe_udp(socket, &[], wait)).unwrap());
+        assert!(started.elapsed() < Duration::from_millis(100));
+        assert!(!block_on(network.probe_udp(socket, &[], wait)).unwrap());
+    }
+}
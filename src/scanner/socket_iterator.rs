@@ -1,30 +1,62 @@
+use crate::address::{self, Indexed, TargetSet};
 use itertools::{iproduct, Product};
-use std::net::{IpAddr, SocketAddr};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::net::SocketAddr;
 
 pub struct SocketIterator<'s> {
-    // product_it is a cartesian product iterator over
-    // the slices of ports and IP addresses.
+    order: Order<'s>,
+}
+
+enum Order<'s> {
+    // A cartesian product iterator over the slices of ports and IP
+    // addresses.
     //
     // The IP/port order is intentionally reversed here since we want
     // the itertools::iproduct! macro below to generate the pairs with
     // all the IPs for one port before moving on to the next one
     // ("hold the port, go through all the IPs, then advance the port...").
     // See also the comments in the iterator implementation for an example.
-    product_it:
-        Product<Box<std::slice::Iter<'s, u16>>, Box<std::slice::Iter<'s, std::net::IpAddr>>>,
+    Product(Product<Box<std::slice::Iter<'s, u16>>, Box<address::Iter<'s>>>),
+    // Every pair is numbered as in the product above, and the numbers are
+    // gone through in the order of the permutation.
+    Permuted {
+        ips: Indexed<'s>,
+        hosts: u128,
+        ports: &'s [u16],
+        permutation: Permutation,
+    },
 }
 
-/// An iterator that receives a slice of IPs and ports and returns a Socket
-/// for each IP and port pair until all of these combinations are exhausted.
-/// The goal of this iterator is to go over every IP and port combination
-/// without generating a big memory footprint. The alternative would be
-/// generating a vector containing all these combinations.
+/// An iterator that receives a set of IPs and a slice of ports and returns a
+/// Socket for each IP and port pair until all of these combinations are
+/// exhausted. The goal of this iterator is to go over every IP and port
+/// combination without generating a big memory footprint. The alternative
+/// would be generating a vector containing all these combinations. The IPs
+/// themselves are only yielded by the set when needed.
 impl<'s> SocketIterator<'s> {
-    pub fn new(ips: &'s [IpAddr], ports: &'s [u16]) -> Self {
+    pub fn new(ips: &'s TargetSet, ports: &'s [u16]) -> Self {
         let ports_it = Box::new(ports.iter());
         let ips_it = Box::new(ips.iter());
         Self {
-            product_it: iproduct!(ports_it, ips_it),
+            order: Order::Product(iproduct!(ports_it, ips_it)),
+        }
+    }
+
+    /// Goes over the same combinations in a random order, given by `seed`,
+    /// so that consecutive sockets rarely share their IP or their port.
+    pub fn permuted(ips: &'s TargetSet, ports: &'s [u16], seed: u64) -> Self {
+        let hosts = ips.len();
+        // A product past u128, only reached by the whole IPv6 space, is cut
+        // short: it could never be scanned anyway.
+        let len = hosts.saturating_mul(ports.len() as u128);
+        Self {
+            order: Order::Permuted {
+                ips: ips.indexed(),
+                hosts,
+                ports,
+                permutation: Permutation::new(len, seed),
+            },
         }
     }
 }
@@ -44,15 +76,85 @@ impl Iterator for SocketIterator<'_> {
     /// it.next(); // 192.168.0.1:443
     /// it.next(); // None
     fn next(&mut self) -> Option<Self::Item> {
-        self.product_it
-            .next()
-            .map(|(port, ip)| SocketAddr::new(*ip, *port))
+        match &mut self.order {
+            Order::Product(product_it) => product_it
+                .next()
+                .map(|(port, ip)| SocketAddr::new(ip, *port)),
+            Order::Permuted {
+                ips,
+                hosts,
+                ports,
+                permutation,
+            } => {
+                let index = permutation.next()?;
+                let ip = ips.get(index % *hosts)?;
+                // The index is below hosts * ports.len(), which fits in usize.
+                let port = ports[(index / *hosts) as usize];
+                Some(SocketAddr::new(ip, port))
+            }
+        }
+    }
+}
+
+/// A permutation of `0..len` that never holds more than its position.
+///
+/// It follows the `Linear Congruential Generator` algorithm, like
+/// `RangeIterator`, over the smallest power of two holding `len`: the
+/// multiplier is one more than a multiple of 4 and the increment is odd,
+/// which goes through every number below the power of two before repeating.
+/// The numbers past `len` are skipped ("cycle walking"), which takes less
+/// than two steps per number on average.
+struct Permutation {
+    len: u128,
+    mask: u128,
+    multiplier: u128,
+    increment: u128,
+    current: u128,
+    remaining: u128,
+}
+
+impl Permutation {
+    fn new(len: u128, seed: u64) -> Self {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mask = len
+            .checked_next_power_of_two()
+            .map_or(u128::MAX, |power| power - 1);
+        Self {
+            len,
+            mask,
+            multiplier: (rng.random::<u128>() & mask & !3) | 1,
+            increment: (rng.random::<u128>() & mask) | 1,
+            current: rng.random::<u128>() & mask,
+            remaining: len,
+        }
+    }
+}
+
+impl Iterator for Permutation {
+    type Item = u128;
+
+    fn next(&mut self) -> Option<u128> {
+        while self.remaining > 0 {
+            let value = self.current;
+            self.current = self
+                .current
+                .wrapping_mul(self.multiplier)
+                .wrapping_add(self.increment)
+                & self.mask;
+            if value < self.len {
+                self.remaining -= 1;
+                return Some(value);
+            }
+        }
+        None
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::SocketIterator;
+    use super::{Permutation, SocketIterator};
+    use crate::address::TargetSet;
+    use std::collections::HashSet;
     use std::net::{IpAddr, SocketAddr};
 
     #[test]
@@ -62,7 +164,8 @@ mod tests {
             "192.168.0.1".parse::<IpAddr>().unwrap(),
         ];
         let ports: Vec<u16> = vec![22, 80, 443];
-        let mut it = SocketIterator::new(&addrs, &ports);
+        let targets = TargetSet::from(&addrs);
+        let mut it = SocketIterator::new(&targets, &ports);
 
         assert_eq!(Some(SocketAddr::new(addrs[0], ports[0])), it.next());
         assert_eq!(Some(SocketAddr::new(addrs[1], ports[0])), it.next());
@@ -72,4 +175,52 @@ mod tests {
         assert_eq!(Some(SocketAddr::new(addrs[1], ports[2])), it.next());
         assert_eq!(None, it.next());
     }
+
+    #[test]
+    fn permutation_goes_through_every_number_once() {
+        for len in [0, 1, 2, 3, 5, 64, 1_000, 65_537] {
+            let mut numbers: Vec<u128> = Permutation::new(len, 7).collect();
+            numbers.sort_unstable();
+            assert_eq!(numbers, (0..len).collect::<Vec<u128>>());
+        }
+    }
+
+    #[test]
+    fn permuted_goes_through_every_ip_port_combination() {
+        let addrs: Vec<IpAddr> = (1..=50)
+            .map(|host| format!("10.0.0.{host}").parse().unwrap())
+            .collect();
+        let ports: Vec<u16> = (1..=100).collect();
+        let targets = TargetSet::from(&addrs);
+
+        let serial: Vec<SocketAddr> = SocketIterator::new(&targets, &ports).collect();
+        let permuted: Vec<SocketAddr> = SocketIterator::permuted(&targets, &ports, 7).collect();
+        assert_ne!(permuted, serial);
+        assert_eq!(
+            permuted.iter().collect::<HashSet<_>>(),
+            serial.iter().collect::<HashSet<_>>()
+        );
+        assert_eq!(permuted.len(), serial.len());
+
+        // The port is no longer held while going through the IPs.
+        let first_ports: HashSet<u16> = permuted[..addrs.len()]
+            .iter()
+            .map(|socket| socket.port())
+            .collect();
+        assert!(first_ports.len() > 1);
+    }
+
+    #[test]
+    fn permuted_repeats_its_order_for_a_seed() {
+        let addrs = vec![
+            "127.0.0.1".parse::<IpAddr>().unwrap(),
+            "192.168.0.1".parse::<IpAddr>().unwrap(),
+        ];
+        let ports: Vec<u16> = (1..=1_000).collect();
+        let targets = TargetSet::from(&addrs);
+        let order = |seed| SocketIterator::permuted(&targets, &ports, seed).collect::<Vec<_>>();
+
+        assert_eq!(order(7), order(7));
+        assert_ne!(order(7), order(8));
+    }
 }
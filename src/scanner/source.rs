@@ -0,0 +1,215 @@
+//! Binds the sockets probes are sent from to a chosen source address,
+//! interface or port, for multi-homed hosts and firewall allow-lists.
+use async_io::Async;
+use async_std::io;
+use async_std::net::{TcpStream, UdpSocket};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+/// Where probes leave from. Anything left to None is chosen by the OS.
+///
+/// Binding to an interface (SO_BINDTODEVICE) is only supported on Linux,
+/// and usually needs root or CAP_NET_RAW.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SourceBinding {
+    pub ip: Option<IpAddr>,
+    pub interface: Option<String>,
+    pub port: Option<u16>,
+}
+
+impl SourceBinding {
+    /// Whether every choice is left to the OS.
+    pub fn is_default(&self) -> bool {
+        self.ip.is_none() && self.interface.is_none() && self.port.is_none()
+    }
+
+    /// Connects to `target` from a socket bound as requested.
+    pub async fn connect(&self, target: SocketAddr) -> io::Result<TcpStream> {
+        let socket = Socket::new(
+            Domain::for_address(target),
+            Type::STREAM,
+            Some(Protocol::TCP),
+        )?;
+        self.bind(&socket, target.ip(), self.port)?;
+        if self.port.is_some() {
+            // Reset rather than linger in TIME_WAIT, which would keep the
+            // next connection to the same socket from using the port.
+            socket.set_linger(Some(Duration::ZERO))?;
+        }
+        socket.set_nonblocking(true)?;
+
+        if let Err(e) = socket.connect(&target.into()) {
+            #[cfg(unix)]
+            let in_progress = e.raw_os_error() == Some(libc::EINPROGRESS);
+            #[cfg(not(unix))]
+            let in_progress = false;
+
+            if !in_progress && e.kind() != io::ErrorKind::WouldBlock {
+                return Err(e);
+            }
+        }
+
+        // The socket becomes writable once the connection is established or failed.
+        let stream = Async::new(std::net::TcpStream::from(socket))?;
+        stream.writable().await?;
+        if let Some(e) = stream.get_ref().take_error()? {
+            return Err(e);
+        }
+        Ok(TcpStream::from(stream.into_inner()?))
+    }
+
+    /// Opens a UDP socket to send probes to `target` from, bound to an OS
+    /// chosen port unless one was chosen.
+    pub fn udp_socket(&self, target: SocketAddr) -> io::Result<UdpSocket> {
+        let socket = Socket::new(
+            Domain::for_address(target),
+            Type::DGRAM,
+            Some(Protocol::UDP),
+        )?;
+        self.bind(&socket, target.ip(), self.port)?;
+        if self.ip.is_none() && self.port.is_none() {
+            socket.bind(&SocketAddr::new(unspecified(target.ip()), 0).into())?;
+        }
+        socket.set_nonblocking(true)?;
+        Ok(UdpSocket::from(std::net::UdpSocket::from(socket)))
+    }
+
+    /// Binds `socket`, meant to reach `target`, to the interface, the source
+    /// address and `port` when they were chosen.
+    pub(crate) fn bind(
+        &self,
+        socket: &Socket,
+        target: IpAddr,
+        port: Option<u16>,
+    ) -> io::Result<()> {
+        if let Some(interface) = &self.interface {
+            bind_device(socket, interface)?;
+        }
+
+        let ip = match self.ip {
+            Some(ip) if ip.is_ipv4() != target.is_ipv4() => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("source address {ip} can't reach {target}"),
+                ));
+            }
+            Some(ip) => ip,
+            None if port.is_some() => unspecified(target),
+            None => return Ok(()),
+        };
+
+        if port.is_some() {
+            // Every probe shares the same source port.
+            socket.set_reuse_address(true)?;
+        }
+        socket.bind(&SocketAddr::new(ip, port.unwrap_or(0)).into())
+    }
+}
+
+fn unspecified(target: IpAddr) -> IpAddr {
+    match target {
+        IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn bind_device(socket: &Socket, interface: &str) -> io::Result<()> {
+    socket.bind_device(Some(interface.as_bytes()))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn bind_device(_socket: &Socket, _interface: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "binding to an interface is only supported on Linux",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SourceBinding;
+    use async_std::task::block_on;
+    use std::io::ErrorKind;
+    use std::net::{IpAddr, SocketAddr, TcpListener};
+
+    fn listener() -> (TcpListener, SocketAddr) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        (listener, addr)
+    }
+
+    #[test]
+    fn connects_from_chosen_address() {
+        let (listener, addr) = listener();
+        let source = SourceBinding {
+            ip: Some("127.0.0.2".parse().unwrap()),
+            ..SourceBinding::default()
+        };
+
+        let stream = block_on(source.connect(addr)).unwrap();
+        let (_, peer) = listener.accept().unwrap();
+
+        assert_eq!(stream.local_addr().unwrap().ip(), source.ip.unwrap());
+        assert_eq!(peer.ip(), source.ip.unwrap());
+    }
+
+    #[test]
+    fn connects_from_chosen_port_repeatedly() {
+        let (listener, addr) = listener();
+        let port = {
+            let free = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+            free.local_addr().unwrap().port()
+        };
+        let source = SourceBinding {
+            port: Some(port),
+            ..SourceBinding::default()
+        };
+
+        let first = block_on(source.connect(addr)).unwrap();
+        assert_eq!(first.local_addr().unwrap().port(), port);
+        drop(first);
+        drop(listener.accept().unwrap());
+
+        let second = block_on(source.connect(addr)).unwrap();
+        assert_eq!(second.local_addr().unwrap().port(), port);
+    }
+
+    #[test]
+    fn refused_connection_is_reported() {
+        let (listener, addr) = listener();
+        drop(listener);
+        let source = SourceBinding {
+            ip: Some("127.0.0.1".parse().unwrap()),
+            ..SourceBinding::default()
+        };
+
+        let error = block_on(source.connect(addr)).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::ConnectionRefused);
+    }
+
+    #[test]
+    fn rejects_source_of_other_family() {
+        let (_listener, addr) = listener();
+        let source = SourceBinding {
+            ip: Some("::1".parse::<IpAddr>().unwrap()),
+            ..SourceBinding::default()
+        };
+
+        let error = block_on(source.connect(addr)).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn udp_socket_uses_chosen_address() {
+        let source = SourceBinding {
+            ip: Some("127.0.0.3".parse().unwrap()),
+            ..SourceBinding::default()
+        };
+        let target: SocketAddr = "127.0.0.1:53".parse().unwrap();
+
+        let socket = source.udp_socket(target).unwrap();
+        assert_eq!(socket.local_addr().unwrap().ip(), source.ip.unwrap());
+    }
+}
@@ -0,0 +1,388 @@
+//! Half-open TCP scans, sending SYN packets crafted on a raw socket instead
+//! of completing a handshake through the OS. Open ports answer with a
+//! SYN-ACK and closed ones with a RST, which the kernel follows up with its
+//! own RST since no socket of ours owns the connection.
+//!
+//! Only IPv4 on Linux is supported, raw sockets need root or CAP_NET_RAW.
+use super::SourceBinding;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::time::Duration;
+
+#[cfg(target_os = "linux")]
+use {
+    async_std::io::timeout,
+    futures::channel::oneshot,
+    log::debug,
+    socket2::{Domain, Protocol, SockAddr, Socket, Type},
+    std::collections::HashMap,
+    std::io::Read,
+    std::net::{IpAddr, SocketAddr},
+    std::sync::atomic::{AtomicBool, Ordering},
+    std::sync::{Arc, Mutex},
+    std::thread,
+};
+
+/// Length of the crafted TCP header, including the MSS option.
+const TCP_HEADER_LEN: usize = 24;
+
+const SYN: u8 = 0x02;
+const RST: u8 = 0x04;
+const ACK: u8 = 0x10;
+
+/// How often the receiving thread checks whether the scanner was dropped.
+#[cfg(target_os = "linux")]
+const RECEIVE_POLL: Duration = Duration::from_millis(100);
+
+/// Sends SYN probes and matches the replies coming back on a raw socket.
+///
+/// Every probe of a scanner uses the same source port and sequence number,
+/// which is enough to tell its replies apart from any other traffic.
+#[cfg(target_os = "linux")]
+pub struct SynScanner {
+    shared: Arc<Shared>,
+}
+
+#[cfg(target_os = "linux")]
+struct Shared {
+    socket: Socket,
+    source: SourceBinding,
+    source_port: u16,
+    sequence: u32,
+    /// Probes waiting for a reply, told whether the port is open.
+    pending: Mutex<HashMap<SocketAddrV4, oneshot::Sender<bool>>>,
+    /// The source address routed to each target being scanned.
+    source_ips: Mutex<HashMap<Ipv4Addr, Ipv4Addr>>,
+    closed: AtomicBool,
+}
+
+#[cfg(target_os = "linux")]
+impl SynScanner {
+    /// Opens the raw socket and starts the thread receiving replies, with
+    /// probes leaving from `source`. Fails with PermissionDenied without
+    /// root or CAP_NET_RAW.
+    pub fn new(source: &SourceBinding) -> io::Result<Self> {
+        let socket = Socket::new(Domain::IPV4, Type::RAW, Some(Protocol::TCP))?;
+        socket.set_read_timeout(Some(RECEIVE_POLL))?;
+        source.bind(&socket, IpAddr::V4(Ipv4Addr::UNSPECIFIED), None)?;
+
+        let shared = Arc::new(Shared {
+            socket,
+            source: source.clone(),
+            source_port: source
+                .port
+                .unwrap_or_else(|| rand::random_range(32_768..61_000)),
+            sequence: rand::random(),
+            pending: Mutex::default(),
+            source_ips: Mutex::default(),
+            closed: AtomicBool::new(false),
+        });
+
+        let receiver = Arc::clone(&shared);
+        thread::Builder::new()
+            .name("syn-receiver".to_owned())
+            .spawn(move || receiver.receive())?;
+
+        Ok(Self { shared })
+    }
+
+    /// Sends a single SYN to `target`, waiting up to `wait` for the reply.
+    ///
+    /// Mirrors a connect: Ok when the port is open, ConnectionRefused when
+    /// it is closed and TimedOut when nothing came back.
+    pub async fn probe(&self, target: SocketAddrV4, wait: Duration) -> io::Result<()> {
+        let (sender, receiver) = oneshot::channel();
+        self.shared.pending.lock().unwrap().insert(target, sender);
+
+        let reply = match self.shared.send_syn(target) {
+            Ok(()) => {
+                timeout(wait, async {
+                    receiver
+                        .await
+                        .map_err(|_| io::Error::other("SYN receiver stopped"))
+                })
+                .await
+            }
+            Err(e) => Err(e),
+        };
+        self.shared.pending.lock().unwrap().remove(&target);
+
+        match reply? {
+            true => Ok(()),
+            false => Err(io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                format!("{target} answered with a RST"),
+            )),
+        }
+    }
+
+    /// Forgets what was kept about `target` once none of its ports are left
+    /// to probe.
+    pub fn host_finished(&self, target: Ipv4Addr) {
+        self.shared.source_ips.lock().unwrap().remove(&target);
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Shared {
+    fn send_syn(&self, target: SocketAddrV4) -> io::Result<()> {
+        let source = SocketAddrV4::new(self.source_ip(*target.ip())?, self.source_port);
+        let packet = syn_packet(source, target, self.sequence);
+        let destination = SockAddr::from(SocketAddr::from(SocketAddrV4::new(*target.ip(), 0)));
+        self.socket.send_to(&packet, &destination)?;
+        Ok(())
+    }
+
+    /// The chosen source address, else the one the kernel routes packets to
+    /// `target` from. Connecting a UDP socket picks the route without
+    /// sending anything.
+    fn source_ip(&self, target: Ipv4Addr) -> io::Result<Ipv4Addr> {
+        if let Some(IpAddr::V4(source)) = self.source.ip {
+            return Ok(source);
+        }
+        if let Some(source) = self.source_ips.lock().unwrap().get(&target) {
+            return Ok(*source);
+        }
+
+        let probe = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+        self.source.bind(&probe, IpAddr::V4(target), None)?;
+        probe.connect(&SocketAddr::from(SocketAddrV4::new(target, 9)).into())?;
+        let source = match probe.local_addr()?.as_socket() {
+            Some(SocketAddr::V4(local)) => *local.ip(),
+            _ => return Err(io::ErrorKind::AddrNotAvailable.into()),
+        };
+
+        self.source_ips.lock().unwrap().insert(target, source);
+        Ok(source)
+    }
+
+    fn receive(&self) {
+        let mut buf = [0u8; 1500];
+        while !self.closed.load(Ordering::Relaxed) {
+            match (&self.socket).read(&mut buf) {
+                Ok(size) => self.dispatch(&buf[..size]),
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) => {}
+                Err(e) => {
+                    debug!("Stopped receiving SYN replies {}", e);
+                    break;
+                }
+            }
+        }
+        // Dropping the senders wakes up any probe still waiting.
+        self.pending.lock().unwrap().clear();
+    }
+
+    fn dispatch(&self, packet: &[u8]) {
+        let Some(reply) = Reply::parse(packet) else {
+            return;
+        };
+        if reply.destination_port != self.source_port
+            || reply.acknowledgment != self.sequence.wrapping_add(1)
+        {
+            return;
+        }
+
+        let open = match reply.flags {
+            flags if flags & (SYN | ACK) == SYN | ACK => true,
+            flags if flags & RST != 0 => false,
+            _ => return,
+        };
+        if let Some(sender) = self.pending.lock().unwrap().remove(&reply.source) {
+            let _ = sender.send(open);
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for SynScanner {
+    fn drop(&mut self) {
+        self.shared.closed.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Stand-in on platforms without Linux raw sockets, which can't be built.
+#[cfg(not(target_os = "linux"))]
+pub struct SynScanner(());
+
+#[cfg(not(target_os = "linux"))]
+impl SynScanner {
+    pub fn new(_source: &SourceBinding) -> io::Result<Self> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "SYN scans are only supported on Linux",
+        ))
+    }
+
+    pub async fn probe(&self, _target: SocketAddrV4, _wait: Duration) -> io::Result<()> {
+        unreachable!("SynScanner can't be built on this platform")
+    }
+
+    pub fn host_finished(&self, _target: Ipv4Addr) {
+        unreachable!("SynScanner can't be built on this platform")
+    }
+}
+
+impl std::fmt::Debug for SynScanner {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SynScanner").finish_non_exhaustive()
+    }
+}
+
+/// The fields of a TCP reply needed to match it with a probe.
+#[derive(Debug, PartialEq, Eq)]
+struct Reply {
+    source: SocketAddrV4,
+    destination_port: u16,
+    acknowledgment: u32,
+    flags: u8,
+}
+
+impl Reply {
+    /// Parses an IPv4 packet as read from a raw socket, IP header included.
+    fn parse(packet: &[u8]) -> Option<Self> {
+        let version = packet.first()? >> 4;
+        let header_len = usize::from(packet.first()? & 0x0f) * 4;
+        if version != 4 || header_len < 20 || *packet.get(9)? != 6 {
+            return None;
+        }
+
+        let source = packet.get(12..16)?;
+        let source_ip = Ipv4Addr::new(source[0], source[1], source[2], source[3]);
+        let tcp = packet.get(header_len..header_len + 20)?;
+        Some(Self {
+            source: SocketAddrV4::new(source_ip, u16::from_be_bytes([tcp[0], tcp[1]])),
+            destination_port: u16::from_be_bytes([tcp[2], tcp[3]]),
+            acknowledgment: u32::from_be_bytes([tcp[8], tcp[9], tcp[10], tcp[11]]),
+            flags: tcp[13],
+        })
+    }
+}
+
+/// Builds the TCP header of a SYN, advertising an MSS of 1460 like most
+/// operating systems do so that the probe does not stand out.
+fn syn_packet(source: SocketAddrV4, target: SocketAddrV4, sequence: u32) -> [u8; TCP_HEADER_LEN] {
+    let mut packet = [0u8; TCP_HEADER_LEN];
+    packet[0..2].copy_from_slice(&source.port().to_be_bytes());
+    packet[2..4].copy_from_slice(&target.port().to_be_bytes());
+    packet[4..8].copy_from_slice(&sequence.to_be_bytes());
+    // Data offset, in 32-bit words.
+    packet[12] = ((TCP_HEADER_LEN / 4) as u8) << 4;
+    packet[13] = SYN;
+    packet[14..16].copy_from_slice(&1024u16.to_be_bytes());
+    // MSS option.
+    packet[20..24].copy_from_slice(&[2, 4, 0x05, 0xb4]);
+
+    let checksum = tcp_checksum(*source.ip(), *target.ip(), &packet);
+    packet[16..18].copy_from_slice(&checksum.to_be_bytes());
+    packet
+}
+
+/// The internet checksum of a TCP segment, over the IPv4 pseudo header.
+fn tcp_checksum(source: Ipv4Addr, target: Ipv4Addr, segment: &[u8]) -> u16 {
+    let mut pseudo_header = Vec::with_capacity(12 + segment.len());
+    pseudo_header.extend_from_slice(&source.octets());
+    pseudo_header.extend_from_slice(&target.octets());
+    pseudo_header.extend_from_slice(&[0, 6]);
+    pseudo_header.extend_from_slice(&(segment.len() as u16).to_be_bytes());
+    pseudo_header.extend_from_slice(segment);
+
+    let mut sum: u32 = pseudo_header
+        .chunks(2)
+        .map(|chunk| u32::from(u16::from_be_bytes([chunk[0], *chunk.get(1).unwrap_or(&0)])))
+        .sum();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{syn_packet, tcp_checksum, Reply, ACK, SYN};
+    use std::net::{Ipv4Addr, SocketAddrV4};
+
+    const SOURCE: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(192, 168, 1, 10), 40_000);
+    const TARGET: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(192, 168, 1, 1), 443);
+
+    #[test]
+    fn syn_packet_has_valid_checksum() {
+        let packet = syn_packet(SOURCE, TARGET, 0xdead_beef);
+
+        assert_eq!(packet[13], SYN);
+        assert_eq!(u16::from_be_bytes([packet[2], packet[3]]), 443);
+        // Summing a segment with its own checksum folds to zero.
+        assert_eq!(tcp_checksum(*SOURCE.ip(), *TARGET.ip(), &packet), 0);
+    }
+
+    #[test]
+    fn parses_syn_ack_reply() {
+        let mut packet = vec![0u8; 40];
+        packet[0] = 0x45;
+        packet[9] = 6;
+        packet[12..16].copy_from_slice(&TARGET.ip().octets());
+        packet[16..20].copy_from_slice(&SOURCE.ip().octets());
+        packet[20..22].copy_from_slice(&443u16.to_be_bytes());
+        packet[22..24].copy_from_slice(&40_000u16.to_be_bytes());
+        packet[28..32].copy_from_slice(&0xdead_bef0u32.to_be_bytes());
+        packet[33] = SYN | ACK;
+
+        assert_eq!(
+            Reply::parse(&packet),
+            Some(Reply {
+                source: TARGET,
+                destination_port: 40_000,
+                acknowledgment: 0xdead_bef0,
+                flags: SYN | ACK,
+            })
+        );
+    }
+
+    #[test]
+    fn ignores_truncated_and_non_tcp_packets() {
+        assert_eq!(Reply::parse(&[0x45, 0, 0]), None);
+        // Long enough to tell the protocol, too short for the source address.
+        for length in 10..16 {
+            let mut packet = vec![0u8; length];
+            packet[0] = 0x45;
+            packet[9] = 6;
+            assert_eq!(Reply::parse(&packet), None);
+        }
+
+        let mut udp = vec![0u8; 40];
+        udp[0] = 0x45;
+        udp[9] = 17;
+        assert_eq!(Reply::parse(&udp), None);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn probes_local_ports() {
+        use super::{SourceBinding, SynScanner};
+        use async_std::task::block_on;
+        use std::io::ErrorKind;
+        use std::net::TcpListener;
+        use std::time::Duration;
+
+        // Raw sockets need privileges the test runner may not have.
+        let Ok(scanner) = SynScanner::new(&SourceBinding::default()) else {
+            return;
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let open = SocketAddrV4::new(Ipv4Addr::LOCALHOST, listener.local_addr().unwrap().port());
+        let closed = {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            SocketAddrV4::new(Ipv4Addr::LOCALHOST, listener.local_addr().unwrap().port())
+        };
+
+        let wait = Duration::from_secs(1);
+        assert!(block_on(scanner.probe(open, wait)).is_ok());
+        assert_eq!(
+            block_on(scanner.probe(closed, wait)).unwrap_err().kind(),
+            ErrorKind::ConnectionRefused
+        );
+    }
+}
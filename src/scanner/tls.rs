@@ -0,0 +1,386 @@
+//! TLS handshakes against open ports, reporting the negotiated parameters
+//! and the certificate the server presented.
+use super::{Protocol, ScanReport, Scanner};
+use crate::address::Targets;
+use async_std::io;
+use futures::stream::FuturesUnordered;
+use futures::StreamExt;
+use futures_rustls::TlsConnector;
+use log::debug;
+use once_cell::sync::Lazy;
+use rustls::client::{ServerCertVerified, ServerCertVerifier};
+use rustls::{Certificate, ClientConfig, ProtocolVersion, ServerName};
+use std::convert::TryFrom;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use x509_parser::certificate::X509Certificate;
+use x509_parser::extensions::GeneralName;
+use x509_parser::prelude::FromDer;
+
+/// What a successful TLS handshake told about a socket.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TlsInfo {
+    /// The negotiated protocol version, such as "TLSv1.3".
+    pub protocol: String,
+    /// The negotiated cipher suite, such as "TLS13_AES_256_GCM_SHA384".
+    pub cipher: String,
+    /// The leaf certificate, None if the server sent none or it could not be parsed.
+    pub certificate: Option<CertificateInfo>,
+}
+
+impl fmt::Display for TlsInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.protocol, self.cipher)?;
+        if let Some(certificate) = &self.certificate {
+            write!(f, ", {certificate}")?;
+        }
+        Ok(())
+    }
+}
+
+/// The fields of a leaf certificate worth looking at while scanning.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CertificateInfo {
+    pub subject: String,
+    pub issuer: String,
+    /// DNS names and IP addresses of the subject alternative name extension.
+    pub sans: Vec<String>,
+    pub not_before: SystemTime,
+    pub not_after: SystemTime,
+    /// Whether the certificate was issued by its own subject.
+    pub self_signed: bool,
+}
+
+impl CertificateInfo {
+    /// Parses a DER encoded certificate.
+    pub fn from_der(der: &[u8]) -> Option<Self> {
+        let (_, certificate) = X509Certificate::from_der(der)
+            .map_err(|e| debug!("Failed to parse certificate {}", e))
+            .ok()?;
+
+        let mut sans = Vec::new();
+        if let Ok(Some(extension)) = certificate.subject_alternative_name() {
+            for name in &extension.value.general_names {
+                match name {
+                    GeneralName::DNSName(name) => sans.push((*name).to_owned()),
+                    GeneralName::IPAddress(bytes) => sans.extend(ip_from_bytes(bytes)),
+                    _ => {}
+                }
+            }
+        }
+
+        let validity = certificate.validity();
+        Some(Self {
+            subject: certificate.subject().to_string(),
+            issuer: certificate.issuer().to_string(),
+            sans,
+            not_before: system_time(validity.not_before.timestamp()),
+            not_after: system_time(validity.not_after.timestamp()),
+            self_signed: certificate.subject().as_raw() == certificate.issuer().as_raw(),
+        })
+    }
+
+    /// Whether the certificate is expired at `now`, or will be within `margin`.
+    pub fn expires_within(&self, now: SystemTime, margin: Duration) -> bool {
+        self.not_after <= now + margin
+    }
+}
+
+impl fmt::Display for CertificateInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "subject \"{}\"", self.subject)?;
+        if !self.sans.is_empty() {
+            write!(f, " [{}]", self.sans.join(", "))?;
+        }
+        if self.self_signed {
+            write!(f, ", self-signed")?;
+        } else {
+            write!(f, ", issuer \"{}\"", self.issuer)?;
+        }
+        write!(
+            f,
+            ", valid from {} to {}",
+            time::OffsetDateTime::from(self.not_before).date(),
+            time::OffsetDateTime::from(self.not_after).date()
+        )
+    }
+}
+
+fn ip_from_bytes(bytes: &[u8]) -> Option<String> {
+    let ip = match bytes.len() {
+        4 => IpAddr::from(<[u8; 4]>::try_from(bytes).ok()?),
+        16 => IpAddr::from(<[u8; 16]>::try_from(bytes).ok()?),
+        _ => return None,
+    };
+    Some(ip.to_string())
+}
+
+fn system_time(timestamp: i64) -> SystemTime {
+    let offset = Duration::from_secs(timestamp.unsigned_abs());
+    if timestamp >= 0 {
+        UNIX_EPOCH + offset
+    } else {
+        UNIX_EPOCH - offset
+    }
+}
+
+/// Accepts whatever certificate the server presents. The probe is there to
+/// report on certificates, self-signed and expired ones included, not to
+/// trust them.
+struct AcceptAnyCertificate;
+
+impl ServerCertVerifier for AcceptAnyCertificate {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+static CONNECTOR: Lazy<TlsConnector> = Lazy::new(|| {
+    let config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(Arc::new(AcceptAnyCertificate))
+        .with_no_client_auth();
+    TlsConnector::from(Arc::new(config))
+});
+
+fn protocol_name(version: ProtocolVersion) -> String {
+    match version {
+        ProtocolVersion::TLSv1_2 => "TLSv1.2".to_owned(),
+        ProtocolVersion::TLSv1_3 => "TLSv1.3".to_owned(),
+        other => format!("{other:?}"),
+    }
+}
+
+impl Scanner {
+    /// Attempts a TLS handshake on every open TCP socket of `report`,
+    /// recording the outcome of the successful ones back into the report.
+    ///
+    /// The first hostname `targets` links to an IP is sent as its SNI, so
+    /// that servers hosting several names present the right certificate.
+    /// Handshakes go through the same rate limit and batch size as the scan
+    /// itself, the timeout only starts once the connection is established.
+    /// Only TLS 1.2 and 1.3 are offered, servers limited to older versions
+    /// are reported as not speaking TLS. A cancelled scanner only waits for
+    /// the handshakes already started.
+    pub async fn probe_tls(&self, report: &mut ScanReport, targets: &Targets) {
+        let mut sockets = report.open_sockets(Protocol::Tcp).into_iter();
+        let mut ftrs = FuturesUnordered::new();
+        for socket in sockets.by_ref().take(self.batch_size.max(1).into()) {
+            ftrs.push(self.tls_handshake(socket, targets));
+        }
+
+        while let Some((socket, result)) = ftrs.next().await {
+            if let Some(socket) = sockets.next().filter(|_| !self.cancellation.is_cancelled()) {
+                ftrs.push(self.tls_handshake(socket, targets));
+            }
+
+            match result {
+                Ok(info) => report.insert_tls(socket, info),
+                Err(e) => debug!("No TLS handshake with {}: {}", socket, e),
+            }
+        }
+    }
+
+    async fn tls_handshake(
+        &self,
+        socket: SocketAddr,
+        targets: &Targets,
+    ) -> (SocketAddr, io::Result<TlsInfo>) {
+        let server_name = targets
+            .hostnames(socket.ip())
+            .iter()
+            .find_map(|hostname| ServerName::try_from(hostname.as_str()).ok())
+            .unwrap_or(ServerName::IpAddress(socket.ip()));
+
+        let result = async {
+            // Connecting has a timeout of its own, after any rate limit wait.
+            let stream = self.connect(socket).await?;
+            let stream = io::timeout(self.timeout, CONNECTOR.connect(server_name, stream)).await?;
+            let (_, connection) = stream.get_ref();
+
+            Ok(TlsInfo {
+                protocol: connection
+                    .protocol_version()
+                    .map_or_else(|| "unknown".to_owned(), protocol_name),
+                cipher: connection.negotiated_cipher_suite().map_or_else(
+                    || "unknown".to_owned(),
+                    |suite| format!("{:?}", suite.suite()),
+                ),
+                certificate: connection
+                    .peer_certificates()
+                    .and_then(|certificates| certificates.first())
+                    .and_then(|leaf| CertificateInfo::from_der(&leaf.0)),
+            })
+        }
+        .await;
+
+        (socket, result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CertificateInfo;
+    use crate::address::Targets;
+    use crate::input::ScanOrder;
+    use crate::port_strategy::PortStrategy;
+    use crate::scanner::{PortState, Protocol, RateLimiter, ScanReport, Scanner};
+    use async_std::task::block_on;
+    use rcgen::generate_simple_self_signed;
+    use std::io::{Read, Write};
+    use std::net::{IpAddr, SocketAddr, TcpListener};
+    use std::sync::{mpsc, Arc};
+    use std::thread;
+    use std::time::{Duration, SystemTime};
+
+    fn self_signed() -> rcgen::Certificate {
+        generate_simple_self_signed(vec!["scanme.test".to_owned(), "127.0.0.1".to_owned()]).unwrap()
+    }
+
+    fn scanner(addrs: &[IpAddr], port: u16) -> Scanner {
+        Scanner::builder(
+            addrs,
+            PortStrategy::pick(&None, Some(vec![port]), ScanOrder::Serial),
+        )
+        .batch_size(10)
+        .timeout(Duration::from_millis(2_000))
+        .build()
+        .unwrap()
+    }
+
+    #[test]
+    fn parses_self_signed_certificate() {
+        let der = self_signed().serialize_der().unwrap();
+        let certificate = CertificateInfo::from_der(&der).unwrap();
+
+        assert!(certificate.self_signed);
+        assert_eq!(certificate.subject, certificate.issuer);
+        assert_eq!(certificate.sans, vec!["scanme.test", "127.0.0.1"]);
+        assert!(certificate.not_before < SystemTime::now());
+        assert!(!certificate.expires_within(SystemTime::now(), Duration::from_secs(86_400)));
+    }
+
+    #[test]
+    fn rejects_garbage_certificate() {
+        assert_eq!(CertificateInfo::from_der(b"not a certificate"), None);
+    }
+
+    /// Serves a single TLS handshake with a self-signed certificate, telling
+    /// the SNI the client sent.
+    fn tls_server() -> (u16, mpsc::Receiver<Option<String>>) {
+        let certificate = self_signed();
+        let config = rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![rustls::Certificate(certificate.serialize_der().unwrap())],
+                rustls::PrivateKey(certificate.serialize_private_key_der()),
+            )
+            .unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut connection = rustls::ServerConnection::new(Arc::new(config)).unwrap();
+            let mut tls = rustls::Stream::new(&mut connection, &mut stream);
+            let _ = tls.write_all(b"hello");
+            let _ = sender.send(tls.conn.server_name().map(str::to_owned));
+            let _ = tls.read(&mut [0u8; 16]);
+        });
+        (port, receiver)
+    }
+
+    #[test]
+    fn reports_tls_details_of_open_socket() {
+        let (port, sni) = tls_server();
+
+        let addrs = vec!["127.0.0.1".parse::<IpAddr>().unwrap()];
+        let socket = SocketAddr::new(addrs[0], port);
+        let mut report = ScanReport::new();
+        report.insert(socket, Protocol::Tcp, PortState::Open);
+
+        block_on(scanner(&addrs, port).probe_tls(&mut report, &Targets::default()));
+
+        let tls = report.tls(socket).unwrap();
+        assert_eq!(tls.protocol, "TLSv1.3");
+        assert!(tls.cipher.starts_with("TLS13_"));
+        assert!(tls.certificate.as_ref().unwrap().self_signed);
+        // No SNI is sent for a bare IP.
+        assert_eq!(sni.recv().unwrap(), None);
+    }
+
+    #[test]
+    fn sends_the_linked_hostname_as_sni() {
+        let (port, sni) = tls_server();
+
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let socket = SocketAddr::new(ip, port);
+        let mut report = ScanReport::new();
+        report.insert(socket, Protocol::Tcp, PortState::Open);
+        let mut targets = Targets::default();
+        targets.link(ip, "scanme.test");
+
+        block_on(scanner(&[ip], port).probe_tls(&mut report, &targets));
+
+        assert!(report.tls(socket).is_some());
+        assert_eq!(sni.recv().unwrap().as_deref(), Some("scanme.test"));
+    }
+
+    #[test]
+    fn rate_limit_wait_is_not_a_handshake_timeout() {
+        let servers = [tls_server(), tls_server()];
+
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let mut report = ScanReport::new();
+        for (port, _) in &servers {
+            report.insert(SocketAddr::new(ip, *port), Protocol::Tcp, PortState::Open);
+        }
+        // The second connection waits 500ms for the rate limit, longer than
+        // the timeout.
+        let ports = servers.iter().map(|(port, _)| *port).collect();
+        let scanner = Scanner::builder(
+            &[ip],
+            PortStrategy::pick(&None, Some(ports), ScanOrder::Serial),
+        )
+        .batch_size(10)
+        .timeout(Duration::from_millis(300))
+        .rate_limiter(RateLimiter::new(None, Some(2)))
+        .build()
+        .unwrap();
+
+        block_on(scanner.probe_tls(&mut report, &Targets::default()));
+
+        assert_eq!(report.tls_sockets().count(), 2);
+    }
+
+    #[test]
+    fn plain_tcp_socket_has_no_tls() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.write_all(b"SSH-2.0-OpenSSH_9.6\r\n").unwrap();
+        });
+
+        let addrs = vec!["127.0.0.1".parse::<IpAddr>().unwrap()];
+        let socket = SocketAddr::new(addrs[0], port);
+        let mut report = ScanReport::new();
+        report.insert(socket, Protocol::Tcp, PortState::Open);
+
+        block_on(scanner(&addrs, port).probe_tls(&mut report, &Targets::default()));
+
+        assert_eq!(report.tls(socket), None);
+    }
+}
@@ -0,0 +1,81 @@
+//! How probes reach the targets. The scanner only ever connects and sends
+//! UDP datagrams through a `Transport`, so a scan can run against the real
+//! network or against a simulated one.
+use super::udp::UdpPool;
+use super::SourceBinding;
+use async_std::io::{self, Read, Write};
+use async_std::net::TcpStream;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use std::fmt;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+/// A stream opened by `Transport::connect`.
+pub trait Connection: Read + Write + Unpin + Send {}
+
+impl<T: Read + Write + Unpin + Send> Connection for T {}
+
+/// Opens the connections and sends the UDP probes of a scan.
+///
+/// The scanner applies its own timeouts, retries and rate limits on top,
+/// a transport only reports what the target did. SYN probes go through raw
+/// sockets of their own and never use it.
+pub trait Transport: fmt::Debug + Send + Sync {
+    /// Connects to `target` over TCP.
+    ///
+    /// A closed port is reported as ConnectionRefused. A target that never
+    /// answers may leave the future pending, the scanner gives up on it.
+    fn connect(&self, target: SocketAddr) -> BoxFuture<'_, io::Result<Box<dyn Connection>>>;
+
+    /// Sends every payload to `target` over UDP at once, waiting up to
+    /// `wait` for an answer to any of them.
+    ///
+    /// Ok(true) when something answered, Ok(false) when nothing did, and
+    /// ConnectionRefused when the port is closed.
+    fn probe_udp<'a>(
+        &'a self,
+        target: SocketAddr,
+        payloads: &'a [&'a [u8]],
+        wait: Duration,
+    ) -> BoxFuture<'a, io::Result<bool>>;
+}
+
+/// The real network, probes leaving from `source`.
+#[derive(Debug)]
+pub struct NetTransport {
+    source: SourceBinding,
+    udp_pool: UdpPool,
+}
+
+impl NetTransport {
+    pub fn new(source: SourceBinding) -> Self {
+        Self {
+            udp_pool: UdpPool::new(source.clone()),
+            source,
+        }
+    }
+}
+
+impl Transport for NetTransport {
+    fn connect(&self, target: SocketAddr) -> BoxFuture<'_, io::Result<Box<dyn Connection>>> {
+        async move {
+            let stream = if self.source.is_default() {
+                TcpStream::connect(target).await?
+            } else {
+                self.source.connect(target).await?
+            };
+            Ok(Box::new(stream) as Box<dyn Connection>)
+        }
+        .boxed()
+    }
+
+    fn probe_udp<'a>(
+        &'a self,
+        target: SocketAddr,
+        payloads: &'a [&'a [u8]],
+        wait: Duration,
+    ) -> BoxFuture<'a, io::Result<bool>> {
+        self.udp_pool.probe(target, payloads, wait).boxed()
+    }
+}
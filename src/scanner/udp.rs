@@ -0,0 +1,430 @@
+//! UDP probes sent from a few shared sockets rather than one socket per
+//! probe, answers being matched back to their probe by the address they come
+//! from. Large UDP scans are then bound by neither the file limit nor
+//! allocations.
+//!
+//! A closed port answers with an ICMP port unreachable. As the shared sockets
+//! aren't connected to the port, the kernel only reports it on their error
+//! queue (IP_RECVERR), which is Linux only. Other platforms still connect a
+//! socket per probe.
+use super::SourceBinding;
+use crate::generated::get_parsed_data;
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+#[cfg(target_os = "linux")]
+use {
+    async_std::io::timeout,
+    futures::channel::oneshot,
+    log::debug,
+    socket2::{Domain, Protocol, SockAddr, Socket, Type},
+    std::net::{IpAddr, Ipv4Addr, Ipv6Addr, UdpSocket},
+    std::os::unix::io::AsRawFd,
+    std::sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    std::sync::{Arc, Mutex},
+    std::thread,
+};
+
+/// The payloads of the nmap payload database, indexed by port.
+static PAYLOADS: Lazy<HashMap<u16, Vec<&'static [u8]>>> = Lazy::new(|| {
+    let mut index: HashMap<u16, Vec<&'static [u8]>> = HashMap::new();
+    for (ports, payloads) in get_parsed_data() {
+        for port in ports {
+            index
+                .entry(*port)
+                .or_default()
+                .extend(payloads.iter().map(Vec::as_slice));
+        }
+    }
+    index
+});
+
+/// Every payload of the nmap payload database meant for `port`.
+pub(crate) fn payloads(port: u16) -> &'static [&'static [u8]] {
+    PAYLOADS.get(&port).map_or(&[], Vec::as_slice)
+}
+
+/// Number of sockets the probes of each IP version are spread over.
+#[cfg(target_os = "linux")]
+const POOL_SIZE: usize = 4;
+
+/// How often the receiving threads check whether the pool was dropped, and
+/// look for errors a send took the notice of.
+#[cfg(target_os = "linux")]
+const RECEIVE_POLL: Duration = Duration::from_millis(100);
+
+/// Sends UDP probes from sockets opened on first use, bound as `source`.
+#[cfg(target_os = "linux")]
+pub(crate) struct UdpPool {
+    source: SourceBinding,
+    v4: Mutex<Vec<Arc<Shared>>>,
+    v6: Mutex<Vec<Arc<Shared>>>,
+    next: AtomicUsize,
+}
+
+#[cfg(target_os = "linux")]
+struct Shared {
+    socket: UdpSocket,
+    /// Probes waiting for an answer, told whether it was an error.
+    pending: Mutex<HashMap<SocketAddr, oneshot::Sender<io::Result<()>>>>,
+    closed: AtomicBool,
+}
+
+#[cfg(target_os = "linux")]
+impl UdpPool {
+    pub(crate) fn new(source: SourceBinding) -> Self {
+        Self {
+            source,
+            v4: Mutex::default(),
+            v6: Mutex::default(),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Sends every payload to `target` at once, waiting up to `wait` for
+    /// an answer to any of them.
+    ///
+    /// Ok(true) when something answered, Ok(false) when nothing did, and
+    /// ConnectionRefused when the port is closed.
+    pub(crate) async fn probe(
+        &self,
+        target: SocketAddr,
+        payloads: &[&[u8]],
+        wait: Duration,
+    ) -> io::Result<bool> {
+        // Datagrams to an unspecified address go to this host, the answers
+        // come from loopback.
+        let target = match target.ip() {
+            IpAddr::V4(ip) if ip.is_unspecified() => {
+                SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), target.port())
+            }
+            IpAddr::V6(ip) if ip.is_unspecified() => {
+                SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), target.port())
+            }
+            _ => target,
+        };
+        let shared = self.socket(target)?;
+        let (sender, receiver) = oneshot::channel();
+        shared.pending.lock().unwrap().insert(target, sender);
+
+        let answer = match shared.send(target, payloads) {
+            Ok(()) => {
+                timeout(wait, async {
+                    receiver
+                        .await
+                        .map_err(|_| io::Error::other("UDP receiver stopped"))
+                })
+                .await
+            }
+            Err(e) => Err(e),
+        };
+        shared.pending.lock().unwrap().remove(&target);
+
+        match answer {
+            Ok(answer) => answer.map(|()| true),
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// One of the sockets for the IP version of `target`, in turns.
+    fn socket(&self, target: SocketAddr) -> io::Result<Arc<Shared>> {
+        let mut sockets = match target {
+            SocketAddr::V4(_) => self.v4.lock().unwrap(),
+            SocketAddr::V6(_) => self.v6.lock().unwrap(),
+        };
+        if sockets.is_empty() {
+            // Sockets sharing a source port would steal each other's answers.
+            let size = if self.source.port.is_some() {
+                1
+            } else {
+                POOL_SIZE
+            };
+            *sockets = (0..size)
+                .map(|_| Shared::open(&self.source, target))
+                .collect::<io::Result<_>>()?;
+        }
+        let next = self.next.fetch_add(1, Ordering::Relaxed);
+        Ok(Arc::clone(&sockets[next % sockets.len()]))
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Shared {
+    /// Opens a socket for targets of the IP version of `target` and starts
+    /// the thread receiving its answers.
+    fn open(source: &SourceBinding, target: SocketAddr) -> io::Result<Arc<Self>> {
+        let socket = Socket::new(
+            Domain::for_address(target),
+            Type::DGRAM,
+            Some(Protocol::UDP),
+        )?;
+        let unspecified = match target {
+            SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            SocketAddr::V6(_) => {
+                socket.set_only_v6(true)?;
+                IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+            }
+        };
+        source.bind(&socket, target.ip(), source.port)?;
+        if source.ip.is_none() && source.port.is_none() {
+            socket.bind(&SocketAddr::new(unspecified, 0).into())?;
+        }
+        socket.set_read_timeout(Some(RECEIVE_POLL))?;
+        let socket = UdpSocket::from(socket);
+        receive_errors(&socket, target)?;
+
+        let shared = Arc::new(Self {
+            socket,
+            pending: Mutex::default(),
+            closed: AtomicBool::new(false),
+        });
+        let receiver = Arc::clone(&shared);
+        thread::Builder::new()
+            .name("udp-receiver".to_owned())
+            .spawn(move || receiver.receive())?;
+        Ok(shared)
+    }
+
+    fn send(&self, target: SocketAddr, payloads: &[&[u8]]) -> io::Result<()> {
+        for payload in payloads {
+            // A send fails with the last error of the socket when there is
+            // one, which belongs to another probe. It is on the error queue
+            // as well, handed over right away since the receiving thread
+            // won't be woken up by it anymore, and the send is tried again.
+            let mut sent = self.socket.send_to(payload, target);
+            if sent.is_err() {
+                self.dispatch_errors();
+                sent = self.socket.send_to(payload, target);
+            }
+            sent?;
+        }
+        Ok(())
+    }
+
+    fn receive(&self) {
+        let mut buf = [0u8; 1500];
+        while !self.closed.load(Ordering::Relaxed) {
+            match self.socket.recv_from(&mut buf) {
+                Ok((_, peer)) => self.answer(peer, Ok(())),
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) => {}
+                // The error of a probe, which the error queue tells the
+                // target of.
+                Err(e) => debug!("UDP probe failed {}", e),
+            }
+            self.dispatch_errors();
+        }
+        // Dropping the senders wakes up any probe still waiting.
+        self.pending.lock().unwrap().clear();
+    }
+
+    fn dispatch_errors(&self) {
+        loop {
+            match read_error(&self.socket) {
+                Ok(Some((target, error))) => self.answer(target, Err(error)),
+                Ok(None) => {}
+                Err(e) => {
+                    if e.kind() != io::ErrorKind::WouldBlock {
+                        debug!("Could not read the UDP error queue {}", e);
+                    }
+                    return;
+                }
+            }
+        }
+    }
+
+    fn answer(&self, peer: SocketAddr, answer: io::Result<()>) {
+        if let Some(sender) = self.pending.lock().unwrap().remove(&peer) {
+            let _ = sender.send(answer);
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for UdpPool {
+    fn drop(&mut self) {
+        let v4 = self.v4.lock().unwrap();
+        let v6 = self.v6.lock().unwrap();
+        for shared in v4.iter().chain(v6.iter()) {
+            shared.closed.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Queues the ICMP errors caused by probes sent to targets of the IP version
+/// of `target`, instead of dropping them as an unconnected socket does.
+#[cfg(target_os = "linux")]
+fn receive_errors(socket: &UdpSocket, target: SocketAddr) -> io::Result<()> {
+    let (level, name) = match target {
+        SocketAddr::V4(_) => (libc::SOL_IP, libc::IP_RECVERR),
+        SocketAddr::V6(_) => (libc::SOL_IPV6, libc::IPV6_RECVERR),
+    };
+    let enabled: libc::c_int = 1;
+    // SAFETY: the option value is a c_int living through the call, with its
+    // size given alongside.
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            std::ptr::addr_of!(enabled).cast(),
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Takes the oldest error off the error queue of `socket`: the target of
+/// the probe that failed and why, when the kernel told both. Fails with
+/// WouldBlock once the queue is empty.
+#[cfg(target_os = "linux")]
+fn read_error(socket: &UdpSocket) -> io::Result<Option<(SocketAddr, io::Error)>> {
+    // Aligned for the control message headers.
+    let mut control = [0u64; 32];
+    // SAFETY: recvmsg only writes within the address storage and the control
+    // buffer, both sized in the header, and the control messages are read
+    // back within what it filled.
+    let (errno, target) = unsafe {
+        SockAddr::try_init(|storage, len| {
+            let mut header: libc::msghdr = std::mem::zeroed();
+            header.msg_name = storage.cast();
+            header.msg_namelen = *len;
+            header.msg_control = control.as_mut_ptr().cast();
+            header.msg_controllen = std::mem::size_of_val(&control) as _;
+            let flags = libc::MSG_ERRQUEUE | libc::MSG_DONTWAIT;
+            if libc::recvmsg(socket.as_raw_fd(), &mut header, flags) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            *len = header.msg_namelen;
+
+            let mut message = libc::CMSG_FIRSTHDR(&header);
+            while !message.is_null() {
+                let (level, kind) = ((*message).cmsg_level, (*message).cmsg_type);
+                if (level == libc::SOL_IP && kind == libc::IP_RECVERR)
+                    || (level == libc::SOL_IPV6 && kind == libc::IPV6_RECVERR)
+                {
+                    let error = libc::CMSG_DATA(message)
+                        .cast::<libc::sock_extended_err>()
+                        .read_unaligned();
+                    return Ok(Some(error.ee_errno));
+                }
+                message = libc::CMSG_NXTHDR(&header, message);
+            }
+            Ok(None)
+        })?
+    };
+
+    Ok(errno
+        .zip(target.as_socket())
+        .map(|(errno, target)| (target, io::Error::from_raw_os_error(errno as i32))))
+}
+
+/// Stand-in on platforms without IP_RECVERR, connecting a socket per probe.
+#[cfg(not(target_os = "linux"))]
+pub(crate) struct UdpPool {
+    source: SourceBinding,
+}
+
+#[cfg(not(target_os = "linux"))]
+impl UdpPool {
+    pub(crate) fn new(source: SourceBinding) -> Self {
+        Self { source }
+    }
+
+    /// Sends every payload to `target` at once, waiting up to `wait` for
+    /// an answer to any of them.
+    ///
+    /// Ok(true) when something answered, Ok(false) when nothing did, and
+    /// ConnectionRefused when the port is closed.
+    pub(crate) async fn probe(
+        &self,
+        target: SocketAddr,
+        payloads: &[&[u8]],
+        wait: Duration,
+    ) -> io::Result<bool> {
+        let socket = self.source.udp_socket(target)?;
+        socket.connect(target).await?;
+        for payload in payloads {
+            socket.send(payload).await?;
+        }
+
+        let mut buf = [0u8; 1024];
+        match async_std::io::timeout(wait, socket.recv(&mut buf)).await {
+            Ok(_) => Ok(true),
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl std::fmt::Debug for UdpPool {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("UdpPool").finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{payloads, UdpPool};
+    use crate::scanner::SourceBinding;
+    use async_std::task::block_on;
+    use futures::future::join3;
+    use std::io::ErrorKind;
+    use std::time::Duration;
+
+    #[test]
+    fn payloads_keep_every_entry_of_a_port() {
+        assert_eq!(payloads(137).len(), 3);
+        assert_eq!(payloads(123).len(), 2);
+        // Both entries of 53 are shared with different sets of ports.
+        assert_eq!(payloads(53).len(), 2);
+        assert!(payloads(9).is_empty());
+    }
+
+    #[test]
+    fn answers_reach_their_probe() {
+        // Only answers the second payload, like a service ignoring probes
+        // of other protocols.
+        let server = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        server
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+        let open = server.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let mut buf = [0; 64];
+            while let Ok((size, peer)) = server.recv_from(&mut buf) {
+                if &buf[..size] == b"hello" {
+                    server.send_to(b"hi", peer).unwrap();
+                }
+            }
+        });
+        let silent = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let closed = {
+            let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+            socket.local_addr().unwrap()
+        };
+        let pool = UdpPool::new(SourceBinding::default());
+        let wait = Duration::from_millis(500);
+
+        let (open, silent, closed) = block_on(join3(
+            pool.probe(open, &[b"ping", b"hello"], wait),
+            pool.probe(silent.local_addr().unwrap(), &[b"hello"], wait),
+            pool.probe(closed, &[b"hello"], wait),
+        ));
+
+        assert!(open.unwrap());
+        assert!(!silent.unwrap());
+        assert_eq!(closed.unwrap_err().kind(), ErrorKind::ConnectionRefused);
+    }
+}
@@ -62,7 +62,16 @@
 //!   gathered while parsing available scripts.
 //! - The `{{ip}}` part will be replaced with the ip we got from the scan.
 //! - The `{{port}}` part will be reaplced with the ports separated with the
-//!   `ports_separator` found in the script file
+//!   `ports_separator` found in the script file. These are the TCP ports,
+//!   or the UDP ones when only UDP ports were found.
+//! - The `{{tcp_port}}` and `{{udp_port}}` parts will be replaced with the
+//!   ports of one protocol only.
+//! - The `{{tagged_port}}` part will be replaced with the ports of both
+//!   protocols, prefixed nmap style like `T:22,80,U:53` when both TCP and
+//!   UDP ports were found.
+//! - The `{{scan_flags}}` part will be replaced with the nmap flags scanning
+//!   the protocols of the ports: nothing for TCP, `-sU` for UDP and
+//!   `-sS -sU` for both.
 //!
 //! And when there is only `{{ip}}` and `{{port}}` is in the format, only those
 //! will be replaced with the arguments from the scan.
@@ -93,7 +102,7 @@ use std::os::unix::process::ExitStatusExt;
 static DEFAULT: &str = r#"tags = ["core_approved", "RustScan", "default"]
 developer = [ "RustScan", "https://github.com/RustScan" ]
 ports_separator = ","
-call_format = "nmap -vvv -p {{port}} -{{ipversion}} {{ip}}"
+call_format = "nmap -vvv {{scan_flags}} -p {{tagged_port}} -{{ipversion}} {{ip}}"
 "#;
 
 #[cfg(not(tarpaulin_include))]
@@ -160,6 +169,13 @@ pub fn parse_scripts(scripts: Vec<PathBuf>) -> Vec<ScriptFile> {
     parsed_scripts
 }
 
+/// The ports found open on a host, per protocol.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct OpenPorts {
+    pub tcp: Vec<u16>,
+    pub udp: Vec<u16>,
+}
+
 #[derive(Clone, Debug)]
 #[allow(dead_code)]
 pub struct Script {
@@ -170,7 +186,7 @@ pub struct Script {
     ip: IpAddr,
 
     // Ports found with portscan.
-    open_ports: Vec<u16>,
+    open_ports: OpenPorts,
 
     // Port found in ScriptFile, if defined only this will run with the ip.
     trigger_port: Option<String>,
@@ -190,6 +206,10 @@ struct ExecPartsScript {
     script: String,
     ip: String,
     port: String,
+    tcp_port: String,
+    udp_port: String,
+    tagged_port: String,
+    scan_flags: String,
     ipversion: String,
 }
 
@@ -197,6 +217,10 @@ struct ExecPartsScript {
 struct ExecParts {
     ip: String,
     port: String,
+    tcp_port: String,
+    udp_port: String,
+    tagged_port: String,
+    scan_flags: String,
     ipversion: String,
 }
 
@@ -204,7 +228,7 @@ impl Script {
     pub fn build(
         path: Option<PathBuf>,
         ip: IpAddr,
-        open_ports: Vec<u16>,
+        open_ports: OpenPorts,
         trigger_port: Option<String>,
         ports_separator: Option<String>,
         tags: Option<Vec<String>>,
@@ -228,14 +252,32 @@ impl Script {
 
         let separator = self.ports_separator.unwrap_or_else(|| ",".into());
 
-        let mut ports_str = self
-            .open_ports
-            .iter()
-            .map(ToString::to_string)
-            .collect::<Vec<String>>()
-            .join(&separator);
+        let join = |ports: &[u16]| {
+            ports
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<String>>()
+                .join(&separator)
+        };
+        let tcp_ports_str = join(&self.open_ports.tcp);
+        let udp_ports_str = join(&self.open_ports.udp);
+        // Scripts taking a plain list of ports keep getting one, only the
+        // tagged list tells protocols apart.
+        let (mut ports_str, mut tagged_ports_str, scan_flags) = match (
+            self.open_ports.tcp.is_empty(),
+            self.open_ports.udp.is_empty(),
+        ) {
+            (_, true) => (tcp_ports_str.clone(), tcp_ports_str.clone(), ""),
+            (true, false) => (udp_ports_str.clone(), udp_ports_str.clone(), "-sU"),
+            (false, false) => (
+                tcp_ports_str.clone(),
+                format!("T:{tcp_ports_str}{separator}U:{udp_ports_str}"),
+                "-sS -sU",
+            ),
+        };
         if let Some(port) = self.trigger_port {
-            ports_str = port;
+            ports_str.clone_from(&port);
+            tagged_ports_str = port;
         }
 
         let mut final_call_format = String::new();
@@ -252,6 +294,10 @@ impl Script {
                 script: self.path.unwrap().to_str().unwrap().to_string(),
                 ip: self.ip.to_string(),
                 port: ports_str,
+                tcp_port: tcp_ports_str,
+                udp_port: udp_ports_str,
+                tagged_port: tagged_ports_str,
+                scan_flags: scan_flags.to_owned(),
                 ipversion: match &self.ip {
                     IpAddr::V4(_) => String::from("4"),
                     IpAddr::V6(_) => String::from("6"),
@@ -262,6 +308,10 @@ impl Script {
             let exec_parts: ExecParts = ExecParts {
                 ip: self.ip.to_string(),
                 port: ports_str,
+                tcp_port: tcp_ports_str,
+                udp_port: udp_ports_str,
+                tagged_port: tagged_ports_str,
+                scan_flags: scan_flags.to_owned(),
                 ipversion: match &self.ip {
                     IpAddr::V4(_) => String::from("4"),
                     IpAddr::V6(_) => String::from("6"),
@@ -412,7 +462,10 @@ mod tests {
         Script::build(
             script_f.path,
             "127.0.0.1".parse().unwrap(),
-            vec![80, 8080],
+            OpenPorts {
+                tcp: vec![80, 8080],
+                udp: vec![],
+            },
             script_f.port,
             script_f.ports_separator,
             script_f.tags,
@@ -520,6 +573,47 @@ mod tests {
         assert_eq!(output.trim(), "Total args passed to fixtures/.rustscan_scripts/test_script.pl : 2\nArg # 1 : 127.0.0.1\nArg # 2 : 80,8080");
     }
 
+    #[test]
+    #[cfg(unix)]
+    fn run_script_on_tcp_and_udp_ports() {
+        let script = Script::build(
+            None,
+            "127.0.0.1".parse().unwrap(),
+            OpenPorts {
+                tcp: vec![22, 80],
+                udp: vec![53, 161],
+            },
+            None,
+            Some(",".to_string()),
+            None,
+            Some(
+                "echo {{scan_flags}} {{tagged_port}} {{port}} {{tcp_port}} {{udp_port}}"
+                    .to_string(),
+            ),
+        );
+        let output = script.run().unwrap();
+        assert_eq!(output.trim(), "-sS -sU T:22,80,U:53,161 22,80 22,80 53,161");
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn run_script_on_udp_ports_only() {
+        let script = Script::build(
+            None,
+            "127.0.0.1".parse().unwrap(),
+            OpenPorts {
+                tcp: vec![],
+                udp: vec![53, 161],
+            },
+            None,
+            Some(",".to_string()),
+            None,
+            Some("echo {{scan_flags}} {{tagged_port}} {{port}}".to_string()),
+        );
+        let output = script.run().unwrap();
+        assert_eq!(output.trim(), "-sU 53,161 53,161");
+    }
+
     #[test]
     fn test_custom_directory_config() {
         // Create test config